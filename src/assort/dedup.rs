@@ -0,0 +1,87 @@
+use std::{collections::HashMap, fs, rc::Rc, slice};
+
+use maildir::Maildir;
+use mailparse::MailHeaderMap;
+use tracing::warn;
+
+use crate::{
+    assort::{
+        Error, Options, existing_mails, folders, identical, list_rank,
+        mail::{Mail, Type},
+        parse_all,
+    },
+    config::Config,
+};
+
+/// Finds mails that are stored multiple times and removes all but one copy if `apply` is set.
+///
+/// Copies received via one of the lists in [`Quirks::deduplicate`](crate::config::Quirks) are
+/// removed first, then those not received via the list that comes first in `prefer-list`. With
+/// [`Config::files_copies`], only copies in the same folder are duplicates. Copies that differ
+/// from the kept one (see [`identical`]) are only reported and never removed.
+/// Returns the number of (to be) removed mails.
+pub fn dedup_store(main: Maildir, cfg: &Config, apply: bool) -> Result<usize, Error> {
+    let (folders, _) = folders(main, cfg);
+    let mut mails = existing_mails(&folders, cfg)?;
+    let mut indexed: HashMap<(String, Option<Type>), Vec<Rc<Mail<'_>>>> = HashMap::new();
+    let paths = mails
+        .iter()
+        .map(|(mail, _)| mail.path().to_owned())
+        .collect::<Vec<_>>();
+    let threads = Options::default().parse_threads(cfg);
+    parse_all(&mut mails, cfg, threads, |i, mail| {
+        match mail {
+            Ok(mail) => {
+                let folder = cfg.files_copies().then_some(mail.typ);
+                indexed
+                    .entry((mail.id.clone(), folder))
+                    .or_default()
+                    .push(Rc::new(mail));
+            }
+            Err(e) => warn!("skipping `{}`: {e}", paths[i].display()),
+        }
+        Ok(())
+    })?;
+    let mut removed = 0;
+    for ((id, _), mut mails) in indexed {
+        if mails.len() < 2 {
+            continue;
+        }
+        // stable sort, so the mail in the folder with the highest priority is kept otherwise.
+        mails.sort_by_key(|mail| {
+            let dupe = mail
+                .parsed
+                .headers
+                .get_all_values("list-id")
+                .iter()
+                .any(|id| cfg.quirks.deduplicate.contains(id));
+            (dupe, list_rank(&mail.parsed.headers, cfg))
+        });
+        let name = |mail: &Mail<'_>| match mail.typ {
+            Type::Folder(i) => folders[i].name.clone(),
+            Type::New => unreachable!(),
+        };
+        println!(
+            "`{id}` is stored {} times, keeping {} ({})",
+            mails.len(),
+            mails[0].path.display(),
+            name(&mails[0])
+        );
+        for mail in &mails[1..] {
+            if identical(mail, slice::from_ref(&mails[0]))?.is_none() {
+                println!(
+                    "\tkeeping {} ({}), it differs from the kept copy",
+                    mail.path.display(),
+                    name(mail)
+                );
+                continue;
+            }
+            println!("\tremoving {} ({})", mail.path.display(), name(mail));
+            if apply {
+                fs::remove_file(&mail.path).map_err(Error::Fs)?;
+            }
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
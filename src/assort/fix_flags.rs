@@ -0,0 +1,40 @@
+use std::fs;
+
+use maildir::Maildir;
+
+use crate::{
+    assort::{Error, file_name, folders},
+    config::Config,
+};
+
+/// Renames the mails in `cur` whose flags are not sorted or contain duplicates.
+///
+/// Maildir requires the flags to be in ASCII order, some clients reject mails that don't follow
+/// that. Returns the number of renamed mails.
+pub fn fix_flags(main: Maildir, cfg: &Config) -> Result<usize, Error> {
+    let (folders, _) = folders(main, cfg);
+    let mut fixed = 0;
+    for folder in &folders {
+        for entry in folder.maildir.list_cur() {
+            let entry = entry.map_err(Error::MailIO)?;
+            let mut flags = entry.flags().chars().collect::<Vec<_>>();
+            flags.sort_unstable();
+            flags.dedup();
+            let flags = flags.into_iter().collect::<String>();
+            if flags == entry.flags() {
+                continue;
+            }
+            let dst = entry
+                .path()
+                .with_file_name(file_name(entry.id(), &flags, None, cfg));
+            println!(
+                "{}: `{}` -> `{flags}`",
+                entry.path().display(),
+                entry.flags()
+            );
+            fs::rename(entry.path(), dst).map_err(Error::Fs)?;
+            fixed += 1;
+        }
+    }
+    Ok(fixed)
+}
@@ -1,20 +1,33 @@
-use std::{collections::HashSet, path::Path};
+use std::{collections::HashSet, fmt, path::Path};
 
 use maildir::Maildir;
+use serde::Serialize;
 use thiserror::Error;
 
 use crate::{
     assort::mail::Type,
-    config::{self, Keyword},
+    config::{self, ArchiveBy, Digest, Keyword},
 };
 
 pub struct Folder {
     pub maildir: Maildir,
     pub priority: usize,
     pub keywords: HashSet<Keyword>,
+    pub subject_keywords: HashSet<Keyword>,
+    pub exclude_keywords: HashSet<Keyword>,
+    pub trailers: HashSet<Keyword>,
+    pub from_name: HashSet<Keyword>,
+    pub from_keywords: HashSet<Keyword>,
+    pub min_recipients: Option<usize>,
+    pub max_recipients: Option<usize>,
     pub flagging_keywords: Option<HashSet<Keyword>>,
     pub name: String,
     pub mark_read: bool,
+    pub unread_on_mention: bool,
+    pub set_flags: String,
+    pub copy: bool,
+    pub digest: Option<Digest>,
+    pub archive_by: Option<ArchiveBy>,
 }
 
 impl Folder {
@@ -28,9 +41,109 @@ impl Folder {
             maildir,
             priority: f.priority,
             keywords: f.keywords.clone(),
+            subject_keywords: f.subject_keywords.clone(),
+            exclude_keywords: f.exclude_keywords.clone(),
+            trailers: f.trailers.clone(),
+            from_name: f.from_name.clone(),
+            from_keywords: f.from_keywords.clone(),
+            min_recipients: f.min_recipients,
+            max_recipients: f.max_recipients,
             flagging_keywords: f.flagging_keywords.clone(),
             name: f.name.clone(),
             mark_read: f.mark_read,
+            unread_on_mention: f.unread_on_mention,
+            set_flags: f.set_flags.clone(),
+            copy: f.copy,
+            digest: f.digest,
+            archive_by: f.archive_by,
+        }
+    }
+
+    /// Returns the rule because of which a mail with `fields` belongs into this folder.
+    pub fn matches(&self, fields: &Fields<'_>) -> Option<Rule<'_>> {
+        if self
+            .exclude_keywords
+            .iter()
+            .any(|kw| kw.matches(fields.body))
+        {
+            return None;
+        }
+        if let Some(kw) = self
+            .subject_keywords
+            .iter()
+            .find(|kw| kw.matches(&fields.subject))
+        {
+            return Some(Rule::Subject(kw));
+        }
+        if let Some(kw) = self.keywords.iter().find(|kw| kw.matches(fields.body)) {
+            return Some(Rule::Keyword(kw));
+        }
+        if let Some(kw) = self
+            .trailers
+            .iter()
+            .find(|kw| fields.trailers.iter().any(|t| kw.matches(t)))
+        {
+            return Some(Rule::Trailer(kw));
+        }
+        if let Some(kw) = self
+            .from_name
+            .iter()
+            .find(|kw| fields.from_names.iter().any(|name| kw.matches(name)))
+        {
+            return Some(Rule::FromName(kw));
+        }
+        if let Some(kw) = self
+            .from_keywords
+            .iter()
+            .find(|kw| kw.matches(&fields.from))
+        {
+            return Some(Rule::From(kw));
+        }
+        let (min, max) = (self.min_recipients, self.max_recipients);
+        if (min.is_some() || max.is_some())
+            && min.is_none_or(|min| fields.recipients >= min)
+            && max.is_none_or(|max| fields.recipients <= max)
+        {
+            return Some(Rule::Recipients(fields.recipients));
+        }
+        None
+    }
+
+    /// Creates a folder `name` that is not configured and thus does not match any mails.
+    pub fn unconfigured(name: &str, parent: &Path) -> Self {
+        Self {
+            maildir: Maildir::from(parent.join(format!(".{name}"))),
+            priority: usize::MAX,
+            keywords: HashSet::new(),
+            subject_keywords: HashSet::new(),
+            exclude_keywords: HashSet::new(),
+            trailers: HashSet::new(),
+            from_name: HashSet::new(),
+            from_keywords: HashSet::new(),
+            min_recipients: None,
+            max_recipients: None,
+            name: name.to_owned(),
+            flagging_keywords: None,
+            mark_read: false,
+            unread_on_mention: false,
+            set_flags: String::new(),
+            copy: false,
+            digest: None,
+            archive_by: None,
+        }
+    }
+
+    /// Creates the date based subfolder `name` of the folder `base` with [`Folder::archive_by`].
+    ///
+    /// It does not match any mails by itself, but marks them as read and flags them like `base`.
+    pub fn archive(base: &Folder, name: &str, parent: &Path) -> Self {
+        Self {
+            priority: base.priority,
+            flagging_keywords: base.flagging_keywords.clone(),
+            mark_read: base.mark_read,
+            unread_on_mention: base.unread_on_mention,
+            set_flags: base.set_flags.clone(),
+            ..Self::unconfigured(name, parent)
         }
     }
 
@@ -39,17 +152,106 @@ impl Folder {
             maildir,
             priority: usize::MAX,
             keywords: HashSet::new(),
+            subject_keywords: HashSet::new(),
+            exclude_keywords: HashSet::new(),
+            trailers: HashSet::new(),
+            from_name: HashSet::new(),
+            from_keywords: HashSet::new(),
+            min_recipients: None,
+            max_recipients: None,
             name: "INBOX".to_owned(),
             flagging_keywords: None,
             mark_read: false,
+            unread_on_mention: false,
+            set_flags: String::new(),
+            copy: false,
+            digest: None,
+            archive_by: None,
         }
     }
 }
 
+/// The parts of a mail that [`Folder`]s are matched against.
+pub struct Fields<'m> {
+    pub subject: String,
+    pub body: &'m str,
+    pub trailers: Vec<&'m str>,
+    /// Display names in the `From` header.
+    pub from_names: Vec<String>,
+    /// Values of the author headers, separated by `, `.
+    pub from: String,
+    /// Number of addresses in the `To` and `Cc` headers.
+    pub recipients: usize,
+}
+
+/// The rule of a [`Folder`] matching a mail.
+#[derive(Debug, Clone, Copy)]
+pub enum Rule<'f> {
+    Subject(&'f Keyword),
+    Keyword(&'f Keyword),
+    Trailer(&'f Keyword),
+    FromName(&'f Keyword),
+    From(&'f Keyword),
+    Recipients(usize),
+}
+
+impl fmt::Display for Rule<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Subject(kw) => write!(f, "subject: {kw}"),
+            Rule::Keyword(kw) => write!(f, "keyword: {kw}"),
+            Rule::Trailer(kw) => write!(f, "trailer: {kw}"),
+            Rule::FromName(kw) => write!(f, "from-name: {kw}"),
+            Rule::From(kw) => write!(f, "from: {kw}"),
+            Rule::Recipients(n) => write!(f, "recipients: {n}"),
+        }
+    }
+}
+
+/// Why a new mail is put into its folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    /// The mail did not match anything and goes into the `rest` folder.
+    Default,
+    Thread,
+    Reply,
+    Subject,
+    /// The formatted [`Rule`] of the matching folder.
+    Rule(String),
+    List,
+    EmptyBody,
+    Blocked,
+    MissingMessageId,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reason::Default => "default",
+            Reason::Thread => "thread",
+            Reason::Reply => "reply",
+            Reason::Subject => "subject",
+            Reason::Rule(rule) => rule,
+            Reason::List => "list",
+            Reason::EmptyBody => "empty body",
+            Reason::Blocked => "blocked",
+            Reason::MissingMessageId => "missing Message-ID",
+        })
+    }
+}
+
+/// The maildir flags in their canonical order.
+pub const MAILDIR_FLAGS: &str = "DFPRST";
+
+/// Returns the bit of `flag` in [`Action::flags`], `0` if it isn't a maildir flag.
+fn flag_bit(flag: char) -> u8 {
+    MAILDIR_FLAGS.find(flag).map_or(0, |i| 1 << i)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Action {
-    mark_read: bool,
-    mark_flagged: bool,
+    /// The set of [`MAILDIR_FLAGS`], one bit per flag.
+    flags: u8,
     dest: Dest,
 }
 
@@ -57,19 +259,41 @@ pub struct Action {
 pub enum Dest {
     Drop(DropReason),
     Folder(usize),
+    /// The mail is inconsistent with the store and goes into [`Config::quarantine`], see
+    /// [`Options::skip_errors`](crate::assort::Options::skip_errors).
+    ///
+    /// [`Config::quarantine`]: crate::config::Config::quarantine
+    Quarantine,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum DropReason {
     DuplicateQuirk,
     VerbatimCopy,
     Ignored,
+    EmptyBody,
+    Blocked,
+    Unmatched,
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DropReason::DuplicateQuirk => "duplicate from deduplicated list",
+            DropReason::VerbatimCopy => "verbatim copy",
+            DropReason::Ignored => "ignored list or subject",
+            DropReason::EmptyBody => "empty body",
+            DropReason::Blocked => "blocked sender",
+            DropReason::Unmatched => "matched no folder",
+        })
+    }
 }
 
 impl Dest {
     pub fn max_prio(a: Self, b: Self) -> Option<Self> {
         match (a, b) {
-            (Dest::Drop(_), _) | (_, Dest::Drop(_)) => None,
+            (Dest::Drop(_) | Dest::Quarantine, _) | (_, Dest::Drop(_) | Dest::Quarantine) => None,
             (Dest::Folder(a), Dest::Folder(b)) => Some(Dest::Folder(a.min(b))),
         }
     }
@@ -93,7 +317,7 @@ impl From<Dest> for Option<Type> {
     fn from(value: Dest) -> Self {
         match value {
             Dest::Folder(id) => Some(Type::Folder(id)),
-            Dest::Drop(_) => None,
+            Dest::Drop(_) | Dest::Quarantine => None,
         }
     }
 }
@@ -102,60 +326,92 @@ impl Action {
     pub fn delete(reason: DropReason) -> Self {
         Self {
             dest: Dest::Drop(reason),
-            mark_read: false,
-            mark_flagged: false,
+            flags: 0,
         }
     }
 
     pub fn folder(id: usize) -> Self {
         Self {
             dest: Dest::Folder(id),
-            mark_read: false,
-            mark_flagged: false,
+            flags: 0,
         }
     }
 
-    pub fn with_cleared_flags(&self) -> Self {
-        let Self {
-            mark_read: _,
-            mark_flagged: _,
-            dest,
-        } = self;
+    pub fn quarantine() -> Self {
         Self {
-            dest: *dest,
-            mark_read: false,
-            mark_flagged: false,
+            dest: Dest::Quarantine,
+            flags: 0,
         }
     }
 
-    pub fn flags(&self) -> &'static str {
-        match (self.mark_read, self.mark_flagged) {
-            (true, true) => "FS",
-            (true, false) => "S",
-            (false, true) => "F",
-            (false, false) => "",
+    pub fn with_cleared_flags(&self) -> Self {
+        Self {
+            dest: self.dest,
+            flags: 0,
         }
     }
 
+    pub fn flags(&self) -> String {
+        // maildir flags have to be sorted.
+        MAILDIR_FLAGS
+            .chars()
+            .filter(|&flag| self.flags & flag_bit(flag) != 0)
+            .collect()
+    }
+
+    /// Returns [`Action::flags`] merged with the `existing` flags of the mail.
+    ///
+    /// This keeps flags like `P` (passed) or `D` (draft) that are already set.
+    pub fn merged_flags(&self, existing: &str) -> String {
+        let mut flags = self
+            .flags()
+            .chars()
+            .chain(existing.chars())
+            .collect::<Vec<_>>();
+        flags.sort_unstable();
+        flags.dedup();
+        flags.into_iter().collect()
+    }
+
     pub fn folder_idx(&self) -> Option<usize> {
         match self.dest {
-            Dest::Drop(_) => None,
+            Dest::Drop(_) | Dest::Quarantine => None,
             Dest::Folder(id) => Some(id),
         }
     }
 
     pub fn flag(&mut self) {
-        self.mark_flagged = true;
-        self.mark_read = false;
+        self.flags = (self.flags | flag_bit('F')) & !flag_bit('S');
     }
 
     pub fn read(&mut self) {
-        self.mark_flagged = false;
-        self.mark_read = true;
+        self.flags = (self.flags | flag_bit('S')) & !flag_bit('F');
+    }
+
+    /// Sets the given flags in addition to the ones already set.
+    pub fn add_flags(&mut self, read: bool, flagged: bool, replied: bool) {
+        for (set, flag) in [(read, 'S'), (flagged, 'F'), (replied, 'R')] {
+            if set {
+                self.flags |= flag_bit(flag);
+            }
+        }
+    }
+
+    /// Sets the maildir flags in `flags` (e.g. `"RS"`) in addition to the ones already set.
+    ///
+    /// Characters that aren't maildir flags are ignored.
+    pub fn set_flags(&mut self, flags: &str) {
+        for flag in flags.chars() {
+            self.flags |= flag_bit(flag);
+        }
     }
 
     pub fn is_flagged(&self) -> bool {
-        self.mark_flagged
+        self.flags & flag_bit('F') != 0
+    }
+
+    pub fn is_read(&self) -> bool {
+        self.flags & flag_bit('S') != 0
     }
 
     pub fn dest(&self) -> Dest {
@@ -1,10 +1,14 @@
 use std::{hash::Hash, path::PathBuf};
 
+use encoding_rs::Encoding;
 use maildir::{MailEntry, MailEntryError};
-use mailparse::{MailHeaderMap, ParsedMail};
+use mailparse::{
+    DispositionType, MailAddr, MailHeader, MailHeaderMap, MailParseError, ParsedMail, SingleInfo,
+};
 use thiserror::Error;
+use tracing::warn;
 
-use crate::config::Config;
+use crate::{config::Config, hash::fnv1a};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Type {
@@ -15,8 +19,15 @@ pub enum Type {
 pub struct Mail<'a> {
     pub typ: Type,
     pub id: String,
+    /// The mail has no `Message-ID`, [`Mail::id`] was derived from its content.
+    pub synthetic_id: bool,
     pub maildir_id: String,
+    /// Maildir flags of the mail when it was read.
+    pub flags: String,
+    /// `Message-ID` from the `In-Reply-To` header, or the last one from `References` without it.
     pub parent: Option<String>,
+    /// `Message-ID`s from the `References` header.
+    pub references: Vec<String>,
     pub parsed: ParsedMail<'a>,
     pub path: PathBuf,
 }
@@ -35,13 +46,168 @@ impl Hash for Mail<'_> {
     }
 }
 
+impl Mail<'_> {
+    /// Returns the body used for keyword matching.
+    ///
+    /// With `scan-multipart`, this is the text of all inline `text/plain` parts. With
+    /// `scan-attachments`, the text of patch-like attachments is appended.
+    pub fn body(&self, cfg: &Config) -> Result<String, MailParseError> {
+        let mut body = if cfg.scan_multipart && !self.parsed.subparts.is_empty() {
+            self.text_parts(cfg)?
+        } else {
+            decode_body(&self.parsed, cfg)?
+        };
+        if cfg.scan_attachments {
+            for part in self
+                .parsed
+                .parts()
+                .skip(1)
+                .filter(|part| is_text_attachment(part))
+            {
+                body.push('\n');
+                body.push_str(&decode_body(part, cfg)?);
+            }
+        }
+        Ok(body)
+    }
+
+    /// Returns the `Subject` header with MIME encoded words (e.g. `=?UTF-8?q?...?=`) decoded.
+    pub fn subject(&self) -> Option<String> {
+        self.parsed.headers.get_first_value("Subject")
+    }
+
+    /// Returns the decoded text of all inline `text/plain` parts, separated by newlines.
+    fn text_parts(&self, cfg: &Config) -> Result<String, MailParseError> {
+        let mut text = Vec::new();
+        for part in self.parsed.parts().filter(|part| {
+            part.subparts.is_empty()
+                && part.ctype.mimetype == "text/plain"
+                && part.get_content_disposition().disposition != DispositionType::Attachment
+        }) {
+            text.push(decode_body(part, cfg)?);
+        }
+        Ok(text.join("\n"))
+    }
+
+    /// Returns the headers naming the author of the mail.
+    ///
+    /// These are all headers with the first name in [`Config::author_headers`] that is present.
+    pub fn author_headers(&self, cfg: &Config) -> Vec<&MailHeader<'_>> {
+        cfg.author_headers
+            .iter()
+            .map(|name| self.parsed.headers.get_all_headers(name))
+            .find(|headers| !headers.is_empty())
+            .unwrap_or_default()
+    }
+
+    /// Returns the addresses in the author headers.
+    fn senders(&self, cfg: &Config) -> Vec<SingleInfo> {
+        let mut senders = Vec::new();
+        for header in self.author_headers(cfg) {
+            let Ok(addrs) = mailparse::addrparse_header(header) else {
+                continue;
+            };
+            for addr in addrs.iter() {
+                match addr {
+                    MailAddr::Single(info) => senders.push(info.clone()),
+                    MailAddr::Group(group) => senders.extend(group.addrs.iter().cloned()),
+                }
+            }
+        }
+        senders
+    }
+
+    /// Returns the display names of the addresses in the author headers.
+    pub fn sender_names(&self, cfg: &Config) -> Vec<String> {
+        self.senders(cfg)
+            .into_iter()
+            .filter_map(|info| info.display_name)
+            .collect()
+    }
+
+    /// Returns the email addresses in the author headers.
+    pub fn sender_addresses(&self, cfg: &Config) -> Vec<String> {
+        self.senders(cfg)
+            .into_iter()
+            .map(|info| info.addr)
+            .collect()
+    }
+
+    /// Returns the number of addresses in the `To` and `Cc` headers.
+    pub fn recipient_count(&self) -> usize {
+        let headers = &self.parsed.headers;
+        headers
+            .get_all_headers("To")
+            .into_iter()
+            .chain(headers.get_all_headers("Cc"))
+            .filter_map(|header| mailparse::addrparse_header(header).ok())
+            .flat_map(|addrs| addrs.into_inner())
+            .map(|addr| match addr {
+                MailAddr::Single(_) => 1,
+                MailAddr::Group(group) => group.addrs.len(),
+            })
+            .sum()
+    }
+
+    /// Returns the URL of the mail in the public-inbox at `inbox`.
+    pub fn archive_url(&self, inbox: &str) -> String {
+        let id = self.id.trim_start_matches('<').trim_end_matches('>');
+        let mut url = format!("{}/", inbox.trim_end_matches('/'));
+        for b in id.bytes() {
+            match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => url.push(b as char),
+                b'-' | b'.' | b'_' | b'~' | b'@' | b'!' | b'$' | b'&' | b'\'' | b'(' | b')'
+                | b'*' | b'+' | b',' | b';' | b'=' | b':' => url.push(b as char),
+                _ => url.push_str(&format!("%{b:02X}")),
+            }
+        }
+        url.push('/');
+        url
+    }
+
+    /// Returns whether none of the textual parts of the mail contain any text.
+    pub fn has_empty_body(&self) -> bool {
+        self.parsed
+            .parts()
+            .filter(|part| part.subparts.is_empty() && part.ctype.mimetype.starts_with("text/"))
+            .all(|part| part.get_body().is_ok_and(|body| body.trim().is_empty()))
+    }
+}
+
+/// Decodes the body of `part`, using [`Config::default_charset`] if it doesn't declare a charset.
+fn decode_body(part: &ParsedMail<'_>, cfg: &Config) -> Result<String, MailParseError> {
+    let fallback = cfg
+        .default_charset
+        .as_ref()
+        .filter(|_| !part.ctype.params.contains_key("charset"))
+        .and_then(|charset| Encoding::for_label(charset.as_bytes()));
+    match fallback {
+        Some(encoding) => Ok(encoding.decode(&part.get_body_raw()?).0.into_owned()),
+        None => part.get_body(),
+    }
+}
+
+/// Returns whether `part` is an attachment that might contain a patch.
+fn is_text_attachment(part: &ParsedMail<'_>) -> bool {
+    let disposition = part.get_content_disposition();
+    if disposition.disposition != DispositionType::Attachment {
+        return false;
+    }
+    let name = disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"));
+    part.ctype.mimetype.starts_with("text/")
+        || name.is_some_and(|name| name.ends_with(".patch") || name.ends_with(".diff"))
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("`{0}` is missing an `Message-ID` header.")]
     MissingID(PathBuf),
     #[error("`{1}` has {0} `Message-ID` headers and none are preferred.")]
     MultipleIDs(usize, PathBuf),
-    #[error("`{1}` has {0} `In-Reply-To` headers.")]
+    #[error("`{1}` has {0} `In-Reply-To` headers and none contain a `Message-ID`.")]
     MultiReply(usize, PathBuf),
     #[error("could not parse mail: {0}")]
     MailEntry(#[from] MailEntryError),
@@ -50,9 +216,25 @@ pub enum Error {
 pub fn parse<'a>(mail: &'a mut MailEntry, typ: Type, cfg: &Config) -> Result<Mail<'a>, Error> {
     let path = mail.path().to_owned();
     let maildir_id = mail.id().to_owned();
+    let flags = mail.flags().to_owned();
     let parsed = mail.parsed()?;
     let id = parsed.headers.get_all_headers("Message-ID");
     let id = match id.len() {
+        0 if cfg.quarantine_folder.is_some() => {
+            warn!("`{}` is missing a `Message-ID` header", path.display());
+            return Ok(Mail {
+                maildir_id,
+                flags,
+                id: format!("<lkml.{:016x}@localhost>", fnv1a(parsed.raw_bytes)),
+                synthetic_id: true,
+                parsed,
+                typ,
+                // the mail is quarantined, it should not pull its thread along.
+                parent: None,
+                references: Vec::new(),
+                path,
+            });
+        }
         0 => return Err(Error::MissingID(path)),
         1 => id[0].get_value(),
         len => {
@@ -70,23 +252,178 @@ pub fn parse<'a>(mail: &'a mut MailEntry, typ: Type, cfg: &Config) -> Result<Mai
         .trim_start_matches(|c| c != '<')
         .trim_end_matches(|c| c != '>')
         .to_owned();
-    let parent = parsed.headers.get_all_headers("In-Reply-To");
+    let parent = parsed.headers.get_all_values("In-Reply-To");
     let parent = match parent.len() {
         0 => None,
-        1 => Some(parent[0].get_value()),
-        len => return Err(Error::MultiReply(len, path)),
+        1 => Some(
+            parent[0]
+                .trim_start_matches(|c| c != '<')
+                .trim_end_matches(|c| c != '>')
+                .to_owned(),
+        ),
+        len => {
+            // some clients send multiple `In-Reply-To` headers, use the last valid one.
+            let Some(id) = parent.iter().flat_map(|p| message_ids(p)).last() else {
+                return Err(Error::MultiReply(len, path));
+            };
+            warn!(
+                "`{}` has {len} `In-Reply-To` headers, using `{id}`",
+                path.display()
+            );
+            Some(id)
+        }
     };
-    let parent = parent.map(|p| {
-        p.trim_start_matches(|c| c != '<')
-            .trim_end_matches(|c| c != '>')
-            .to_owned()
-    });
+    let references = parsed
+        .headers
+        .get_all_values("References")
+        .iter()
+        .flat_map(|refs| message_ids(refs))
+        .collect::<Vec<_>>();
+    // the last reference is the parent, if the mail was only threaded via `References`.
+    let parent = parent.or_else(|| references.last().cloned());
     Ok(Mail {
         maildir_id,
+        flags,
         id,
+        synthetic_id: false,
         parsed,
         typ,
         parent,
+        references,
         path,
     })
 }
+
+/// Returns all `<...>` message ids in `value`.
+fn message_ids(value: &str) -> impl Iterator<Item = String> + '_ {
+    value.split('<').skip(1).filter_map(|id| {
+        let (id, _) = id.split_once('>')?;
+        Some(format!("<{id}>"))
+    })
+}
+
+/// Removes all `Re:` prefixes and leading tags like `[PATCH v2 1/3]` from `subject`.
+pub fn normalize_subject(mut subject: &str) -> &str {
+    loop {
+        subject = subject.trim();
+        match subject.get(..3) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("re:") => subject = &subject[3..],
+            _ => match subject
+                .strip_prefix('[')
+                .and_then(|rest| rest.split_once(']'))
+            {
+                Some((_, rest)) => subject = rest,
+                None => return subject,
+            },
+        }
+    }
+}
+
+/// Returns the trailers (e.g. `Reviewed-by: ...`) of the patch in `body`.
+///
+/// Trailers are the `Key: value` lines in the last paragraph of the commit message, which ends at
+/// the `---` line separating it from the diff.
+pub fn trailers(body: &str) -> Vec<&str> {
+    let message = body
+        .lines()
+        .take_while(|line| line.trim_end() != "---")
+        .collect::<Vec<_>>();
+    let Some(paragraph) = message
+        .rsplit(|line| line.trim().is_empty())
+        .find(|paragraph| !paragraph.is_empty())
+    else {
+        return Vec::new();
+    };
+    paragraph
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| {
+            line.split_once(": ").is_some_and(|(key, _)| {
+                !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            })
+        })
+        .collect()
+}
+
+/// Inserts the header `name: value` after the existing headers of the raw mail `raw`.
+pub fn insert_header(raw: &[u8], name: &str, value: &str) -> Vec<u8> {
+    let value = value.replace(['\r', '\n'], " ");
+    let mut pos = raw.len();
+    let mut newline = "\n";
+    if raw.starts_with(b"\r\n") {
+        (pos, newline) = (0, "\r\n");
+    } else if raw.starts_with(b"\n") {
+        pos = 0;
+    } else {
+        for (i, _) in raw.iter().enumerate().filter(|(_, c)| **c == b'\n') {
+            match &raw[i + 1..] {
+                [b'\n', ..] => {
+                    pos = i + 1;
+                    break;
+                }
+                [b'\r', b'\n', ..] => {
+                    (pos, newline) = (i + 1, "\r\n");
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    let mut res = Vec::with_capacity(raw.len() + name.len() + value.len() + 4);
+    res.extend_from_slice(&raw[..pos]);
+    if pos == raw.len() && !raw.is_empty() && !raw.ends_with(b"\n") {
+        res.extend_from_slice(newline.as_bytes());
+    }
+    res.extend_from_slice(format!("{name}: {value}{newline}").as_bytes());
+    res.extend_from_slice(&raw[pos..]);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{Mail, Type, normalize_subject};
+
+    /// Returns the new mail `raw`.
+    fn mail(raw: &str) -> Mail<'_> {
+        Mail {
+            typ: Type::New,
+            id: String::new(),
+            synthetic_id: false,
+            maildir_id: String::new(),
+            flags: String::new(),
+            parent: None,
+            references: Vec::new(),
+            parsed: mailparse::parse_mail(raw.as_bytes()).unwrap(),
+            path: PathBuf::new(),
+        }
+    }
+
+    #[test]
+    fn q_encoded_subject() {
+        let mail = mail("Subject: =?UTF-8?q?=5BPATCH=5D_r=C3=A9sum=C3=A9?=\n\nbody\n");
+        assert_eq!(mail.subject().as_deref(), Some("[PATCH] résumé"));
+    }
+
+    #[test]
+    fn b_encoded_subject() {
+        let mail = mail("Subject: =?UTF-8?B?W1BBVENIXSByw6lzdW3DqQ==?=\n\nbody\n");
+        assert_eq!(mail.subject().as_deref(), Some("[PATCH] résumé"));
+    }
+
+    #[test]
+    fn normalized_subjects() {
+        assert_eq!(normalize_subject("Re: RE:re: foo"), "foo");
+        assert_eq!(
+            normalize_subject("Re: [PATCH v2 1/3] rust: foo"),
+            "rust: foo"
+        );
+        assert_eq!(
+            normalize_subject("[PATCH] [RFC] Re: foo [bar]"),
+            "foo [bar]"
+        );
+        assert_eq!(normalize_subject("[unterminated foo"), "[unterminated foo");
+        assert_eq!(normalize_subject("Reply: foo"), "Reply: foo");
+    }
+}
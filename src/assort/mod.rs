@@ -1,26 +1,49 @@
 use std::{
-    collections::{HashMap, HashSet},
-    io,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    io::{self, BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
     rc::Rc,
+    sync::{Mutex, mpsc},
+    thread,
+    time::{Duration, Instant},
 };
 
+use directories_next::BaseDirs;
 use folder::DropReason;
-use maildir::{MailEntry, MailEntryError, Maildir};
-use mailparse::{MailHeaderMap, MailParseError};
-use tempdir::TempDir;
+use maildir::{MailEntry, MailEntryError, Maildir, MaildirError};
+use mailparse::{MailHeader, MailHeaderMap, MailParseError};
+use serde::Serialize;
 use thiserror::Error;
-use tracing::{error, info, trace, warn};
+use tracing::{Span, debug, error, field, info, info_span, instrument, trace, warn};
 
 use crate::{
     assort::{
-        folder::{Action, Dest, Folder},
+        folder::{Action, Dest, Fields, Folder, Reason},
         mail::{Mail, Type},
     },
-    config::Config,
+    config::{ArchiveBy, Config, Digest, EmptyBody, MaildirDialect, Resurface, Unmatched},
+    file::move_file,
 };
 
+mod dedup;
+mod fix_flags;
 mod folder;
 mod mail;
+mod notmuch;
+mod reassort;
+mod reprocess;
+#[cfg(test)]
+mod tests;
+mod verify;
+
+pub use dedup::dedup_store;
+pub use fix_flags::fix_flags;
+pub use folder::MAILDIR_FLAGS;
+pub use reassort::reassort;
+pub use reprocess::reprocess;
+pub use verify::verify;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -36,39 +59,338 @@ pub enum Error {
     Mail2(#[from] mail::Error),
     #[error("TODO: {0}")]
     Mail3(#[from] MailParseError),
+    #[error("while writing the index: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("while storing a mail: {0}")]
+    Store(#[from] MaildirError),
+    #[error("while tagging the mails: {0}")]
+    Notmuch(#[from] notmuch::Error),
+    #[error("there is no folder called `{0}`")]
+    UnknownFolder(String),
+    #[error(
+        "refusing to delete {0} mails, which is more than `max-deletions` ({1}); check your config \
+        or use `--allow-deletions` to delete them anyway"
+    )]
+    TooManyDeletions(usize, usize),
+    #[error(
+        "`{0}` already exists, an earlier `reassort` was interrupted; assort the mails in it with \
+        `lkml assort` and remove it"
+    )]
+    Reassort(PathBuf),
+}
+
+/// Command line options influencing the assortment.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options<'a> {
+    /// Don't read the mails already stored in the folders.
+    ///
+    /// Replies to those mails will not find their parent and are assorted by their content only.
+    pub only_new: bool,
+    /// Number of threads parsing mails, overrides [`Config::parse_concurrency`].
+    pub parse_concurrency: Option<usize>,
+    /// Append the subject and the start of the body of mails that did not match any folder to
+    /// this file.
+    pub dump_unmatched: Option<&'a Path>,
+    /// Number of body lines to write with [`Options::dump_unmatched`].
+    pub dump_unmatched_lines: usize,
+    /// Print why each dropped duplicate was considered one.
+    pub explain_dedup: bool,
+    /// Only print where the new mails would go, using the rules that don't need the body.
+    pub headers_only: bool,
+    /// Ignore [`Config::max_deletions`].
+    pub allow_deletions: bool,
+    /// Only print what would be done with the new mails, without moving or deleting anything.
+    pub dry_run: bool,
+    /// Write the [`Summary`] as JSON to this file.
+    pub report: Option<&'a Path>,
+    /// Move new mails that are inconsistent with the store into [`Config::quarantine`] instead of
+    /// aborting.
+    pub skip_errors: bool,
+    /// The new mails are the ones of the configured folders and the `INBOX`, which are assorted
+    /// again, see [`reassort`]. Only the mails of the other folders are read from the store and
+    /// no digests are delivered.
+    pub reassort: bool,
+}
+
+impl Options<'_> {
+    /// Returns the number of threads to parse mails with.
+    fn parse_threads(&self, cfg: &Config) -> usize {
+        self.parse_concurrency
+            .or(cfg.parse_concurrency)
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+            .max(1)
+    }
+}
+
+/// What happened to the new mails in a run.
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    /// Number of new mails, without the copies of mails that are already stored or downloaded.
+    pub new: usize,
+    /// Number of mails delivered to each folder.
+    pub delivered: BTreeMap<String, usize>,
+    /// Number of deleted mails per reason.
+    pub dropped: BTreeMap<DropReason, usize>,
+    /// Number of mails moved into [`Config::quarantine`].
+    pub quarantined: usize,
+    /// Number of delivered mails marked as read.
+    pub read: usize,
+    /// Number of delivered mails marked as flagged.
+    pub flagged: usize,
+}
+
+impl Summary {
+    /// Returns the message for the commit of the delivered mails.
+    ///
+    /// The subject contains the number of new mails and the body the per-folder counts. With
+    /// `trailers`, these are also added as [trailers](Self::trailers).
+    pub fn commit_message(&self, trailers: bool) -> String {
+        let mut message = format!("update: {} new mail(s)\n\n{self}", self.new);
+        if trailers && !self.delivered.is_empty() {
+            message.push('\n');
+            message.push_str(&self.trailers());
+        }
+        message.trim_end().to_owned()
+    }
+
+    /// Returns one `Lkml-Folder: <folder>=<count>` git trailer per folder that received mails.
+    pub fn trailers(&self) -> String {
+        self.delivered
+            .iter()
+            .map(|(folder, count)| format!("Lkml-Folder: {folder}={count}\n"))
+            .collect()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (folder, count) in &self.delivered {
+            writeln!(f, "{folder}: {count} new mail(s)")?;
+        }
+        for (reason, count) in &self.dropped {
+            writeln!(f, "deleted {count} mail(s): {reason}")?;
+        }
+        if self.quarantined > 0 {
+            writeln!(f, "quarantined {} mail(s)", self.quarantined)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn run(new: Maildir, main: Maildir, cfg: &Config, opts: Options<'_>) -> Result<Summary, Error> {
+    let Collected {
+        mut folders,
+        mut mails,
+        new_count,
+        rest,
+    } = collect_mails(new, main, cfg, opts)?;
+    let indexed = index(new_count, &mut mails, cfg, opts)?;
+    let Planned {
+        mut plan, indexed, ..
+    } = plan(indexed, &mut folders, rest, cfg, opts)?;
+    if let Some(path) = opts.dump_unmatched {
+        dump_unmatched(&plan, rest, path, opts.dump_unmatched_lines)?;
+    }
+    if let (Some(max), false) = (cfg.max_deletions, opts.allow_deletions) {
+        let deletions = plan
+            .actions
+            .values()
+            .filter(|action| matches!(action.dest(), Dest::Drop(_)))
+            .count();
+        if deletions > max {
+            return Err(Error::TooManyDeletions(deletions, max));
+        }
+    }
+    if !opts.dry_run {
+        create_list_folders(&plan, &folders)?;
+    }
+    archive(&mut plan, &mut folders, rest, &indexed, !opts.dry_run)?;
+    let summary = perform(plan, &folders, rest, cfg, opts)?;
+    if let Some(path) = opts.report {
+        let file = File::create(path).map_err(Error::Fs)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &summary)?;
+    }
+    Ok(summary)
 }
 
-pub fn run(new_dir: TempDir, main: Maildir, cfg: &Config) -> Result<(), Error> {
-    let new = Maildir::from(new_dir.path().to_owned());
+#[derive(Serialize)]
+struct IndexEntry<'i> {
+    path: &'i Path,
+    /// `None` for newly downloaded mails.
+    folder: Option<&'i str>,
+    parent: Option<&'i str>,
+    subject: Option<String>,
+}
+
+/// Collects and indexes the mails like [`run`], but writes the index as JSON to `path` instead of
+/// assorting them.
+pub fn dump_index(
+    new: Maildir,
+    main: Maildir,
+    cfg: &Config,
+    opts: Options<'_>,
+    path: &Path,
+) -> Result<(), Error> {
     let Collected {
         folders,
         mut mails,
         new_count,
+        ..
+    } = collect_mails(new, main, cfg, opts)?;
+    let Indexed { indexed, .. } = index(new_count, &mut mails, cfg, opts)?;
+    let dump = indexed
+        .iter()
+        .map(|(id, mails)| {
+            let mails = mails
+                .iter()
+                .map(|mail| IndexEntry {
+                    path: &mail.path,
+                    folder: match mail.typ {
+                        Type::New => None,
+                        Type::Folder(i) => Some(&folders[i].name),
+                    },
+                    parent: mail.parent.as_deref(),
+                    subject: mail.subject(),
+                })
+                .collect::<Vec<_>>();
+            (id, mails)
+        })
+        .collect::<BTreeMap<_, _>>();
+    let file = File::create(path).map_err(Error::Fs)?;
+    serde_json::to_writer_pretty(BufWriter::new(file), &dump)?;
+    Ok(())
+}
+
+/// Prints where the new mails would be put without moving them.
+///
+/// Only the rules using headers are applied: threads, `subject-keywords`, `from-name`,
+/// `from-keywords`, recipient limits, mailing list folders and blocked senders. Keywords and
+/// trailers never match, since the bodies are not decoded. Nothing in the store is changed.
+pub fn preview(new: Maildir, main: Maildir, cfg: &Config, opts: Options<'_>) -> Result<(), Error> {
+    let opts = Options {
+        headers_only: true,
+        dry_run: true,
+        ..opts
+    };
+    let Collected {
+        mut folders,
+        mut mails,
+        new_count,
         rest,
-    } = collect_mails(new, main, cfg)?;
+    } = collect_mails(new, main, cfg, opts)?;
+    let indexed = index(new_count, &mut mails, cfg, opts)?;
+    let Planned { plan, new, .. } = plan(indexed, &mut folders, rest, cfg, opts)?;
+    for mail in &new {
+        let dest = match plan.actions[mail].dest() {
+            Dest::Drop(reason) => format!("deleted: {reason}"),
+            Dest::Quarantine => format!("quarantined in {}", cfg.quarantine()),
+            Dest::Folder(i) => {
+                let reason = plan.reasons.get(mail).unwrap_or(&Reason::Default);
+                format!("{} ({reason})", folders[i].name)
+            }
+        };
+        let subject = mail.subject();
+        println!("{dest}: {}", subject.unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Runs the assortment of the mails in `corpus` without performing it and prints timings.
+///
+/// Nothing in the store is changed, not even missing folders are created.
+pub fn bench(corpus: Maildir, main: Maildir, cfg: &Config, opts: Options<'_>) -> Result<(), Error> {
+    let opts = Options {
+        dry_run: true,
+        ..opts
+    };
+    let start = Instant::now();
+    let Collected {
+        mut folders,
+        mut mails,
+        new_count,
+        rest,
+    } = collect_mails(corpus, main, cfg, opts)?;
+    let collected = Instant::now();
+    let total = mails.len();
+    let indexed = index(new_count, &mut mails, cfg, opts)?;
+    let indexed_at = Instant::now();
+    let Planned { assorted, .. } = plan(indexed, &mut folders, rest, cfg, opts)?;
+    let end = Instant::now();
+    let elapsed = end - start;
+    println!("mails:   {new_count} new, {total} total");
+    println!("collect: {:?}", collected - start);
+    println!("index:   {:?}", indexed_at - collected);
+    println!("assort:  {:?}", assorted - indexed_at);
+    println!("fixup:   {:?}", end - assorted);
+    println!(
+        "total:   {elapsed:?} ({:.0} mails/s)",
+        total as f64 / elapsed.as_secs_f64()
+    );
+    Ok(())
+}
+
+/// The result of [`plan`].
+struct Planned<'a> {
+    plan: Plan<'a>,
+    indexed: HashMap<String, Vec<Rc<Mail<'a>>>>,
+    new: Vec<Rc<Mail<'a>>>,
+    /// When the initial assortment was complete, before the threads were fixed up.
+    assorted: Instant,
+}
+
+/// Plans where the new mails of `indexed` go, adding the list folders they need to `folders`.
+///
+/// Nothing is changed in the store, this is shared by [`run`], [`preview`] and [`bench`].
+fn plan<'a>(
+    indexed: Indexed<'a>,
+    folders: &mut Vec<Folder>,
+    rest: usize,
+    cfg: &Config,
+    opts: Options<'_>,
+) -> Result<Planned<'a>, Error> {
     let Indexed {
         indexed,
+        subjects,
+        replies,
         new,
-        mut actions,
-    } = index(new_count, &mut mails, cfg)?;
+        actions,
+    } = indexed;
+    add_list_folders(folders, rest, &new, cfg);
+    let ctx = Context {
+        oversized: oversized_threads(&indexed, cfg),
+        indexed: &indexed,
+        subjects: &subjects,
+        replies: &replies,
+        folders,
+        cfg,
+        rest,
+        headers_only: opts.headers_only,
+        skip_errors: opts.skip_errors,
+    };
+    let mut plan = Plan {
+        actions,
+        reasons: HashMap::new(),
+        copies: HashMap::new(),
+        unread: HashSet::new(),
+        unmatched: Vec::new(),
+    };
     let mut new_threads = HashSet::new();
+    let span = info_span!("assort", mails = new.len(), actions = field::Empty).entered();
     for new in &new {
-        assort(
-            new,
-            &indexed,
-            &mut actions,
-            &folders,
-            cfg,
-            rest,
-            &mut new_threads,
-        )?;
+        assort(new, &ctx, &mut plan, &mut new_threads)?;
     }
+    span.record("actions", plan.actions.len());
+    span.exit();
     info!("initial assortment complete");
-    fixup_thread_siblings(&new, &indexed, &mut actions, &folders, cfg)?;
-    perform(actions, &folders)?;
-    // keep it alive until at least here.
-    drop(new_dir);
-    Ok(())
+    let assorted = Instant::now();
+    unify_threads(&new, &ctx, &mut plan)?;
+    fixup_thread_siblings(&new, &ctx, &mut plan)?;
+    Ok(Planned {
+        plan,
+        indexed,
+        new,
+        assorted,
+    })
 }
 
 struct Collected {
@@ -78,12 +400,15 @@ struct Collected {
     rest: usize,
 }
 
-fn collect_mails(new: Maildir, main: Maildir, cfg: &Config) -> Result<Collected, Error> {
+/// Returns the folders sorted by priority and the index of the rest folder.
+fn folders(main: Maildir, cfg: &Config) -> (Vec<Folder>, usize) {
+    let root = main.path().to_owned();
     let mut folders = cfg
         .folders
         .iter()
-        .map(|f| Folder::new(f, main.path()))
+        .map(|f| Folder::new(f, &root))
         .collect::<Vec<_>>();
+    // stable sort, so folders with the same priority are tried in the order of the config.
     folders.sort_by_key(|f| std::cmp::Reverse(f.priority));
     let rest = folders
         .iter()
@@ -92,38 +417,162 @@ fn collect_mails(new: Maildir, main: Maildir, cfg: &Config) -> Result<Collected,
             folders.push(Folder::rest(main));
             folders.len() - 1
         });
-    for folder in &folders {
-        folder.maildir.create_dirs().map_err(Error::Fs)?;
+    for folder in &cfg.folders {
+        if folder.archive_by.is_none() {
+            continue;
+        }
+        let prefix = format!(".{}.", folder.name);
+        let base = folders.iter().position(|f| f.name == folder.name).unwrap();
+        for entry in fs::read_dir(&root).into_iter().flatten().flatten() {
+            let name = entry.file_name();
+            let Some(suffix) = name.to_str().and_then(|name| name.strip_prefix(&prefix)) else {
+                continue;
+            };
+            if !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_digit() || c == '-') {
+                let name = format!("{}.{suffix}", folder.name);
+                folders.push(Folder::archive(&folders[base], &name, &root));
+            }
+        }
     }
-    let newmail = Maildir::from(new.path().to_owned());
-    let mut mails = folders
+    if cfg.auto_list_folders {
+        // `read_dir` fails if the store does not exist yet, then there are no list folders either.
+        for entry in fs::read_dir(&root).into_iter().flatten().flatten() {
+            let name = entry.file_name();
+            let Some(name) = name.to_str().and_then(|name| name.strip_prefix('.')) else {
+                continue;
+            };
+            if name.starts_with("lists.") && !folders.iter().any(|f| f.name == name) {
+                folders.push(Folder::unconfigured(name, &root));
+            }
+        }
+    }
+    let empty_body = match &cfg.empty_body {
+        Some(EmptyBody::Folder(name)) => Some(name),
+        _ => None,
+    };
+    for name in empty_body.into_iter().chain(&cfg.quarantine_folder) {
+        if !folders.iter().any(|f| f.name == *name) {
+            folders.push(Folder::unconfigured(name, &root));
+        }
+    }
+    (folders, rest)
+}
+
+/// Returns the name of the folder for the mailing list of `mail` with
+/// [`Config::auto_list_folders`].
+fn list_folder(mail: &Mail<'_>) -> Option<String> {
+    let id = mail.parsed.headers.get_first_value("List-Id")?;
+    let id = id
+        .rsplit_once('<')
+        .and_then(|(_, id)| id.split_once('>'))
+        .map_or(id.as_str(), |(id, _)| id)
+        .trim();
+    if id.is_empty() {
+        return None;
+    }
+    let id = id
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    Some(format!("lists.{id}"))
+}
+
+/// Adds the missing folders for the mailing lists of the `new` mails with
+/// [`Config::auto_list_folders`].
+///
+/// They are only created by [`create_list_folders`] once it is known which of them receive mails.
+fn add_list_folders(folders: &mut Vec<Folder>, rest: usize, new: &[Rc<Mail<'_>>], cfg: &Config) {
+    if !cfg.auto_list_folders {
+        return;
+    }
+    let root = folders[rest].maildir.path().to_owned();
+    for name in new.iter().filter_map(|mail| list_folder(mail)) {
+        if !folders.iter().any(|f| f.name == name) {
+            folders.push(Folder::unconfigured(&name, &root));
+        }
+    }
+}
+
+/// Creates the folders added by [`add_list_folders`] that mails are put into by `plan`.
+fn create_list_folders(plan: &Plan<'_>, folders: &[Folder]) -> Result<(), Error> {
+    let used = plan
+        .actions
+        .values()
+        .filter_map(Action::folder_idx)
+        .chain(plan.copies.values().flatten().copied());
+    for i in used {
+        let maildir = &folders[i].maildir;
+        if !maildir.path().exists() {
+            maildir.create_dirs().map_err(Error::Fs)?;
+        }
+    }
+    Ok(())
+}
+
+/// Lists the mails already stored in `folders`.
+///
+/// The mails in [`Config::quarantine`] are skipped, they share their `Message-ID` with other
+/// stored or downloaded mails.
+fn existing_mails(folders: &[Folder], cfg: &Config) -> Result<Vec<(MailEntry, Type)>, Error> {
+    folders
         .iter()
         .enumerate()
+        .filter(|(_, f)| f.name != cfg.quarantine())
         .flat_map(|(i, f)| {
             f.maildir
                 .list_new()
                 .chain(f.maildir.list_cur())
                 .map(move |m| (m, i))
         })
-        .map(|(m, i)| Ok::<_, Error>((m.map_err(Error::MailIO)?, Type::Folder(i))))
-        .collect::<Result<Vec<_>, _>>()?;
-    let mut dupe = Vec::with_capacity(100);
+        .map(|(m, i)| Ok((m.map_err(Error::MailIO)?, Type::Folder(i))))
+        .collect()
+}
+
+#[instrument(skip_all, fields(new = field::Empty, stored = field::Empty))]
+fn collect_mails(
+    new: Maildir,
+    main: Maildir,
+    cfg: &Config,
+    opts: Options<'_>,
+) -> Result<Collected, Error> {
+    let (folders, rest) = folders(main, cfg);
+    if !opts.dry_run {
+        for folder in &folders {
+            folder.maildir.create_dirs().map_err(Error::Fs)?;
+        }
+    }
+    let newmail = Maildir::from(new.path().to_owned());
+    let mut mails = if opts.only_new {
+        Vec::new()
+    } else {
+        existing_mails(&folders, cfg)?
+    };
+    if opts.reassort {
+        mails.retain(|(_, typ)| !matches!(typ, Type::Folder(i) if reassorted(&folders[*i], cfg)));
+    }
+    let mut new = Vec::with_capacity(100);
     let mut new_count = 0;
     for mail in newmail.list_cur().chain(newmail.list_new()) {
         new_count += 1;
         let mut mail = mail.map_err(Error::MailIO)?;
-        if mail
-            .headers()?
+        let headers = mail.headers()?;
+        let dupe = headers
             .get_all_values("list-id")
             .iter()
-            .any(|id| cfg.quirks.deduplicate.contains(id))
-        {
-            dupe.push((mail, Type::New));
-        } else {
-            mails.push((mail, Type::New));
-        }
+            .any(|id| cfg.quirks.deduplicate.contains(id));
+        let rank = list_rank(&headers, cfg);
+        new.push(((dupe, rank), mail));
     }
-    mails.extend(dupe);
+    // stable sort, copies from deduplicated lists come last and those from preferred lists first,
+    // so `index` keeps the right copy.
+    new.sort_by_key(|(key, _)| *key);
+    mails.extend(new.into_iter().map(|(_, mail)| (mail, Type::New)));
+    Span::current()
+        .record("new", new_count)
+        .record("stored", mails.len() - new_count);
     Ok(Collected {
         folders,
         mails,
@@ -132,23 +581,159 @@ fn collect_mails(new: Maildir, main: Maildir, cfg: &Config) -> Result<Collected,
     })
 }
 
+/// Returns whether the mails of `folder` are assorted again by [`reassort`].
+///
+/// These are the configured folders and the `INBOX`, but not archive, list or other folders.
+fn reassorted(folder: &Folder, cfg: &Config) -> bool {
+    folder.name == "INBOX" || cfg.folders.iter().any(|f| f.name == folder.name)
+}
+
+/// Returns the position of the first list of a mail in
+/// [`Quirks::prefer_list`](crate::config::Quirks), `usize::MAX` if it wasn't received via one.
+fn list_rank(headers: &[MailHeader<'_>], cfg: &Config) -> usize {
+    headers
+        .get_all_values("list-id")
+        .iter()
+        .filter_map(|id| cfg.quirks.prefer_list.iter().position(|list| list == id))
+        .min()
+        .unwrap_or(usize::MAX)
+}
+
 struct Indexed<'a> {
     indexed: HashMap<String, Vec<Rc<Mail<'a>>>>,
+    /// Stored mails by their normalized subject, only filled with [`Config::subject_threading`].
+    subjects: HashMap<String, Vec<Rc<Mail<'a>>>>,
+    /// Stored replies by the `Message-ID` of their parent, only filled with
+    /// [`Config::follow_replies`].
+    replies: HashMap<String, Vec<Rc<Mail<'a>>>>,
     new: Vec<Rc<Mail<'a>>>,
     actions: HashMap<Rc<Mail<'a>>, Action>,
 }
 
+/// Parses `mails` using `threads` threads and calls `f` with the index and result of every mail,
+/// in the order of `mails`.
+///
+/// The threads take the next mail from a shared queue and hand the parsed mails to `f` through a
+/// bounded channel, so they only get a few mails ahead of it. Stops at the first error of `f`.
+fn parse_all<'a>(
+    mails: &'a mut [(MailEntry, Type)],
+    cfg: &Config,
+    threads: usize,
+    mut f: impl FnMut(usize, Result<Mail<'a>, mail::Error>) -> Result<(), Error>,
+) -> Result<(), Error> {
+    if threads <= 1 || mails.len() < 2 {
+        for (i, (mail, typ)) in mails.iter_mut().enumerate() {
+            f(i, mail::parse(mail, *typ, cfg))?;
+        }
+        return Ok(());
+    }
+    let queue = Mutex::new(mails.iter_mut().enumerate());
+    let (tx, rx) = mpsc::sync_channel(threads);
+    thread::scope(|s| {
+        for _ in 0..threads {
+            let (queue, tx) = (&queue, tx.clone());
+            s.spawn(move || {
+                loop {
+                    let next = queue.lock().expect("parsing thread panicked").next();
+                    let Some((i, (mail, typ))) = next else {
+                        break;
+                    };
+                    // the receiver is gone if `f` failed.
+                    if tx.send((i, mail::parse(mail, *typ, cfg))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+        // the threads finish their mails out of order.
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (i, mail) in rx {
+            pending.insert(i, mail);
+            while let Some(mail) = pending.remove(&next) {
+                f(next, mail)?;
+                next += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Prints why `mail` is considered a duplicate of the already indexed `existing` mails.
+///
+/// For every existing copy, the number of body lines that only appear in one of the two is shown.
+fn explain_duplicate(
+    mail: &Mail<'_>,
+    existing: &[Rc<Mail<'_>>],
+    strategy: &str,
+) -> Result<(), Error> {
+    println!("duplicate {} ({strategy})", mail.id);
+    println!("  dropped: {}", mail.path.display());
+    let body = mail.parsed.get_body()?;
+    let lines = body.lines().collect::<HashSet<_>>();
+    for other in existing {
+        let other_body = other.parsed.get_body()?;
+        let other_lines = other_body.lines().collect::<HashSet<_>>();
+        println!(
+            "  kept:    {} ({} line(s) vs {}, {} only in the dropped, {} only in the kept mail)",
+            other.path.display(),
+            body.lines().count(),
+            other_body.lines().count(),
+            lines.difference(&other_lines).count(),
+            other_lines.difference(&lines).count(),
+        );
+    }
+    Ok(())
+}
+
+/// Returns how `mail` is identical to the `existing` mails with the same `Message-ID`: all have
+/// the same raw bytes or one has the same body.
+fn identical(mail: &Mail<'_>, existing: &[Rc<Mail<'_>>]) -> Result<Option<&'static str>, Error> {
+    if existing
+        .iter()
+        .all(|m| mail.parsed.raw_bytes == m.parsed.raw_bytes)
+    {
+        return Ok(Some("identical raw bytes"));
+    }
+    for other in existing {
+        if mail.parsed.get_body()? == other.parsed.get_body()? {
+            return Ok(Some("identical body"));
+        }
+    }
+    Ok(None)
+}
+
+#[instrument(skip_all, fields(mails = mails.len(), new = field::Empty, actions = field::Empty))]
 fn index<'a>(
     new_count: usize,
     mails: &'a mut [(MailEntry, Type)],
     cfg: &Config,
+    opts: Options<'_>,
 ) -> Result<Indexed<'a>, Error> {
     let mut indexed: HashMap<String, Vec<Rc<Mail<'a>>>> = HashMap::with_capacity(mails.len());
+    let mut subjects: HashMap<String, Vec<Rc<Mail<'a>>>> = HashMap::new();
+    let mut replies: HashMap<String, Vec<Rc<Mail<'a>>>> = HashMap::new();
     let mut new = Vec::with_capacity(new_count);
-    let mut error = false;
+    let mut offending = Vec::new();
     let mut actions = HashMap::with_capacity(new_count);
-    for (mail, typ) in mails {
-        let mail = Rc::new(mail::parse(mail, *typ, cfg)?);
+    parse_all(mails, cfg, opts.parse_threads(cfg), |_, mail| {
+        let mail = Rc::new(mail?);
+        if let (true, Some(parent), Type::Folder(_)) = (cfg.follow_replies, &mail.parent, mail.typ)
+        {
+            replies
+                .entry(parent.clone())
+                .or_default()
+                .push(mail.clone());
+        }
+        if cfg.subject_threading && mail.typ != Type::New {
+            if let Some(subject) = mail.subject() {
+                subjects
+                    .entry(mail::normalize_subject(&subject).to_owned())
+                    .or_default()
+                    .push(mail.clone());
+            }
+        }
         let mails = indexed.entry(mail.id.clone()).or_default();
         if !mails.is_empty() && mail.typ == Type::New {
             if mail
@@ -159,18 +744,36 @@ fn index<'a>(
                 .any(|id| cfg.quirks.deduplicate.contains(id))
             {
                 trace!("dropping {} because of duplicate & wrong list", mail.id);
+                if opts.explain_dedup {
+                    explain_duplicate(&mail, mails, "duplicate from deduplicated list")?;
+                }
                 actions.insert(mail.clone(), Action::delete(DropReason::DuplicateQuirk));
-            } else if mails
-                .iter()
-                .all(|m| mail.parsed.raw_bytes == m.parsed.raw_bytes)
-                || mails
+            } else if let Some(strategy) = identical(&mail, mails)? {
+                // the new mails from preferred lists come first, so this is only a different
+                // reason if the stored copy is from one.
+                let rank = list_rank(&mail.parsed.headers, cfg);
+                let (reason, strategy) = if mails
                     .iter()
-                    .map(|m| Ok(mail.parsed.get_body()? == m.parsed.get_body()?))
-                    .reduce(|a: Result<bool, Error>, b| Ok(a? || b?))
-                    .unwrap()?
-            {
-                trace!("dropping verbatim copy {}", mail.id);
-                actions.insert(mail.clone(), Action::delete(DropReason::VerbatimCopy));
+                    .any(|m| list_rank(&m.parsed.headers, cfg) < rank)
+                {
+                    (
+                        DropReason::DuplicateQuirk,
+                        "copy from a less preferred list",
+                    )
+                } else {
+                    (DropReason::VerbatimCopy, strategy)
+                };
+                trace!("dropping {} ({strategy})", mail.id);
+                if opts.explain_dedup {
+                    explain_duplicate(&mail, mails, strategy)?;
+                }
+                actions.insert(mail.clone(), Action::delete(reason));
+            } else if opts.skip_errors {
+                warn!(
+                    "`{}` has the same id as a stored mail but differs, quarantining it",
+                    mail.path.display()
+                );
+                actions.insert(mail.clone(), Action::quarantine());
             } else {
                 error!(
                     "new email received with same id as existing, pls implement!\n{:#?} vs\n{}\n\n {:#?}",
@@ -178,86 +781,291 @@ fn index<'a>(
                     mail.path.display(),
                     mail.parsed.headers.get_all_values("list-id")
                 );
-                error = true
+                offending.extend(mails.iter().chain([&mail]).map(|m| m.path.clone()));
+            }
+        } else if !cfg.files_copies() && mails.iter().any(|m| m.typ != mail.typ) {
+            let paths = mails
+                .iter()
+                .chain(std::iter::once(&mail))
+                .map(|m| (m.path.display(), m.typ))
+                .collect::<Vec<_>>();
+            // stored mails are never quarantined, `verify` and `dedup-store` can fix them.
+            if opts.skip_errors {
+                warn!("duplicate mails aren't stored in the same directory: {paths:?}");
+            } else {
+                error!("duplicate mails aren't stored in the same directory! {paths:?}");
+                offending.extend(mails.iter().chain([&mail]).map(|m| m.path.clone()));
             }
-        } else if mails.iter().any(|m| m.typ != mail.typ) {
-            error!(
-                "duplicate mails aren't stored in the same directory! {:?}",
-                mails
-                    .iter()
-                    .chain(std::iter::once(&mail))
-                    .map(|m| (m.path.display(), m.typ))
-                    .collect::<Vec<_>>()
-            );
-            error = true;
         }
-        if *typ == Type::New {
+        if mail.typ == Type::New {
             new.push(mail.clone());
         }
         trace!("{}", mail.id);
         mails.push(mail);
-    }
-    if error {
+        Ok(())
+    })?;
+    if !offending.is_empty() {
         eprintln!("An error occurred with duplicate emails above. If you report the error,");
         eprintln!("please include the offending email files.");
         eprintln!();
-        eprintln!("Press enter to terminate the program & delete the temporary directory.");
-        io::stdin()
-            .read_line(&mut String::new())
-            .expect("failed to read from stdin");
+        wait_for_user(cfg, &offending);
         return Err(Error::Internal);
     }
+    Span::current()
+        .record("new", new.len())
+        .record("actions", actions.len());
     Ok(Indexed {
         indexed,
+        subjects,
+        replies,
         new,
         actions,
     })
 }
 
-fn assort<'a>(
-    new: &Rc<Mail<'a>>,
+/// State shared by the assortment steps.
+struct Context<'a, 'c> {
+    indexed: &'c HashMap<String, Vec<Rc<Mail<'a>>>>,
+    subjects: &'c HashMap<String, Vec<Rc<Mail<'a>>>>,
+    replies: &'c HashMap<String, Vec<Rc<Mail<'a>>>>,
+    folders: &'c [Folder],
+    cfg: &'c Config,
+    rest: usize,
+    /// `Message-ID`s of all mails in threads larger than [`Config::max_thread_size`].
+    oversized: HashSet<String>,
+    /// Don't decode the bodies, keywords and trailers never match, see [`preview`].
+    headers_only: bool,
+    /// Quarantine mails instead of failing, see [`Options::skip_errors`].
+    skip_errors: bool,
+}
+
+impl Context<'_, '_> {
+    /// Returns whether new mails are put into the folder of the stored mails of their thread.
+    ///
+    /// With [`Config::notmuch`], all stored mails are in the `rest` folder, so they don't tell where
+    /// their thread belongs.
+    fn stored_threads(&self) -> bool {
+        self.cfg.notmuch.is_none()
+    }
+}
+
+/// Returns the `Message-ID` of the oldest known ancestor of `mail`.
+fn thread_root<'i, 'a>(
+    mut mail: &'i Mail<'a>,
+    indexed: &'i HashMap<String, Vec<Rc<Mail<'a>>>>,
+) -> &'i str {
+    let mut seen = HashSet::new();
+    while let Some(parent) = mail.parent.as_ref().and_then(|p| indexed.get(p)) {
+        // guard against `In-Reply-To` cycles.
+        if !seen.insert(&parent[0].id) {
+            break;
+        }
+        mail = &parent[0];
+    }
+    &mail.id
+}
+
+/// Moves the mails going into folders with [`Folder::archive_by`] into the subfolder for the date
+/// of their thread root, which is added (and created if `create` is set) if it doesn't exist.
+fn archive<'a>(
+    plan: &mut Plan<'a>,
+    folders: &mut Vec<Folder>,
+    rest: usize,
     indexed: &HashMap<String, Vec<Rc<Mail<'a>>>>,
-    actions: &mut HashMap<Rc<Mail<'a>>, Action>,
-    folders: &[Folder],
+    create: bool,
+) -> Result<(), Error> {
+    let root = folders[rest].maildir.path().to_owned();
+    for (mail, action) in &mut plan.actions {
+        let Some(idx) = action.folder_idx() else {
+            continue;
+        };
+        let Some(archive_by) = folders[idx].archive_by else {
+            continue;
+        };
+        let first = &indexed[thread_root(mail, indexed)][0];
+        let date = first
+            .parsed
+            .headers
+            .get_first_value("Date")
+            .and_then(|date| mailparse::dateparse(&date).ok())
+            .and_then(|ts| chrono::DateTime::from_timestamp(ts, 0))
+            .unwrap_or_else(chrono::Utc::now);
+        let suffix = match archive_by {
+            ArchiveBy::Month => date.format("%Y-%m"),
+            ArchiveBy::Year => date.format("%Y"),
+        };
+        let name = format!("{}.{suffix}", folders[idx].name);
+        let sub = match folders.iter().position(|f| f.name == name) {
+            Some(sub) => sub,
+            None => {
+                let sub = Folder::archive(&folders[idx], &name, &root);
+                if create {
+                    sub.maildir.create_dirs().map_err(Error::Fs)?;
+                }
+                folders.push(sub);
+                folders.len() - 1
+            }
+        };
+        action.set_dest(Dest::Folder(sub));
+    }
+    Ok(())
+}
+
+fn oversized_threads(
+    indexed: &HashMap<String, Vec<Rc<Mail<'_>>>>,
     cfg: &Config,
-    rest: usize,
+) -> HashSet<String> {
+    let Some(max) = cfg.max_thread_size else {
+        return HashSet::new();
+    };
+    let mut sizes = HashMap::<&str, usize>::new();
+    for mails in indexed.values() {
+        *sizes.entry(thread_root(&mails[0], indexed)).or_default() += 1;
+    }
+    for (root, size) in &sizes {
+        if *size > max {
+            warn!("thread of `{root}` has {size} mails, not keeping it in a single folder");
+        }
+    }
+    indexed
+        .iter()
+        .filter(|(_, mails)| sizes[thread_root(&mails[0], indexed)] > max)
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+/// Changes to perform on the store.
+struct Plan<'a> {
+    actions: HashMap<Rc<Mail<'a>>, Action>,
+    /// Why the mails are put into their folder.
+    reasons: HashMap<Rc<Mail<'a>>, Reason>,
+    /// Additional folders to put a copy of the mails into.
+    copies: HashMap<Rc<Mail<'a>>, Vec<usize>>,
+    /// Already stored mails to mark as unread.
+    unread: HashSet<Rc<Mail<'a>>>,
+    /// New mails that did not belong to a thread and did not match any folder.
+    unmatched: Vec<Rc<Mail<'a>>>,
+}
+
+fn assort<'a>(
+    new: &Rc<Mail<'a>>,
+    ctx: &Context<'a, '_>,
+    plan: &mut Plan<'a>,
     new_threads: &mut HashSet<Rc<Mail<'a>>>,
 ) -> Result<Action, Error> {
-    if let Some(action) = actions.get(new) {
+    let Context {
+        indexed,
+        folders,
+        cfg,
+        rest,
+        ..
+    } = *ctx;
+    if let Some(action) = plan.actions.get(new) {
         return Ok(*action);
     }
+    if let Some(block) = &cfg.block {
+        if new.sender_addresses(cfg).iter().any(|addr| {
+            block
+                .addresses
+                .iter()
+                .any(|blocked| blocked.eq_ignore_ascii_case(addr))
+        }) {
+            debug!("dropping `{}` from blocked sender", new.id);
+            let action = Action::delete(DropReason::Blocked);
+            plan.actions.insert(new.clone(), action);
+            plan.reasons.insert(new.clone(), Reason::Blocked);
+            return Ok(action);
+        }
+    }
+    if let (true, Some(name)) = (new.synthetic_id, &cfg.quarantine_folder) {
+        let i = folders
+            .iter()
+            .position(|f| f.name == *name)
+            .ok_or(Error::Internal)?;
+        let action = Action::folder(i);
+        plan.actions.insert(new.clone(), action);
+        plan.reasons.insert(new.clone(), Reason::MissingMessageId);
+        return Ok(action);
+    }
     let mut is_new_thread = false;
     let mut action = None;
-    if let Some(parent) = new.parent.as_ref() {
-        if let Some(parents) = indexed.get(parent) {
-            let parent = &parents[0];
-            match &parent.typ {
-                Type::New => {
-                    if new_threads.contains(parent) {
-                        is_new_thread = true;
-                        new_threads.insert(new.clone());
-                    }
-                    let parent_action =
-                        actions.get(parent).copied().map(Ok).unwrap_or_else(|| {
-                            assort(parent, indexed, actions, folders, cfg, rest, new_threads)
-                        })?;
-                    action = Some(parent_action.with_cleared_flags());
-                }
-                Type::Folder(id) => {
-                    action = Some(Action::folder(*id));
+    let mut reason = Reason::Default;
+    let mut stored_parent = None;
+    let parent = new
+        .parent
+        .as_ref()
+        .filter(|_| !ctx.oversized.contains(&new.id));
+    let parents = parent
+        .and_then(|parent| indexed.get(parent))
+        .filter(|parents| ctx.stored_threads() || parents[0].typ == Type::New);
+    if let Some(parents) = parents {
+        let parent = &parents[0];
+        match &parent.typ {
+            Type::New => {
+                if new_threads.contains(parent) {
+                    is_new_thread = true;
+                    new_threads.insert(new.clone());
                 }
+                let parent_action = plan
+                    .actions
+                    .get(parent)
+                    .copied()
+                    .map(Ok)
+                    .unwrap_or_else(|| assort(parent, ctx, plan, new_threads))?;
+                action = Some(parent_action.with_cleared_flags());
+                reason = Reason::Thread;
             }
-        } else {
+            Type::Folder(id) => {
+                action = Some(Action::folder(*id));
+                reason = Reason::Thread;
+                stored_parent = Some(parent.clone());
+            }
+        }
+    } else if let Some(stored) = subject_parent(new, ctx).filter(|_| ctx.stored_threads()) {
+        debug!(
+            "`{}` has no known parent, using the folder of `{}` with the same subject",
+            new.id, stored.id
+        );
+        if let Type::Folder(id) = stored.typ {
+            action = Some(Action::folder(id));
+            reason = Reason::Subject;
+            stored_parent = Some(stored.clone());
+        }
+    } else {
+        if let Some(parent) = parent.filter(|parent| !indexed.contains_key(*parent)) {
             warn!(
                 "parent mail with id `{parent}` not found in any folder (parent of {})",
                 new.path.display()
             );
-            new_threads.insert(new.clone());
         }
-    } else {
         new_threads.insert(new.clone());
     }
-    let body = new.parsed.get_body()?;
+    if action.is_none() && !ctx.oversized.contains(&new.id) && ctx.stored_threads() {
+        if let Some(Type::Folder(id)) = ctx.replies.get(&new.id).map(|replies| replies[0].typ) {
+            debug!("`{}` arrived after its stored reply, following it", new.id);
+            action = Some(Action::folder(id));
+            reason = Reason::Reply;
+            new_threads.remove(new);
+        }
+    }
+    let body = if ctx.headers_only {
+        String::new()
+    } else {
+        new.body(cfg)?
+    };
+    let fields = Fields {
+        subject: new.subject().unwrap_or_default(),
+        body: &body,
+        trailers: mail::trailers(&body),
+        from_names: new.sender_names(cfg),
+        from: new
+            .author_headers(cfg)
+            .iter()
+            .map(|header| header.get_value())
+            .collect::<Vec<_>>()
+            .join(", "),
+        recipients: new.recipient_count(),
+    };
     if action.is_none() || is_new_thread {
         let folders = if let Some(action) = action {
             folders
@@ -268,71 +1076,202 @@ fn assort<'a>(
             folders.iter().enumerate().take(folders.len())
         };
         for (i, folder) in folders {
-            if folder.keywords.iter().any(|kw| kw.matches(&body)) {
+            if let Some(rule) = folder.matches(&fields) {
+                if i != rest {
+                    if let Some(why) = ignored(new, cfg) {
+                        debug!(
+                            "`{}` ignored because of {why} kept in `{}`, because it matched ({rule})",
+                            new.id, folder.name
+                        );
+                    }
+                }
                 action = Some(Action::folder(i));
+                reason = Reason::Rule(rule.to_string());
                 break;
             }
         }
     }
-    let mut action = action.unwrap_or_else(|| Action::folder(rest));
-
-    if action.folder_idx() == Some(rest)
-        && !action.is_flagged()
-        && cfg
-            .ignore
-            .as_ref()
-            .map(|ignore| {
-                new.parsed
-                    .headers
-                    .get_all_values("List-Id")
-                    .iter()
-                    .any(|id| ignore.lists.contains(id))
-                    && !new
-                        .parsed
-                        .headers
-                        .get_all_values("to")
+    let mut action = match (action, &cfg.empty_body) {
+        (Some(action), _) => action,
+        (None, Some(policy)) if !ctx.headers_only && new.has_empty_body() => {
+            reason = Reason::EmptyBody;
+            match policy {
+                EmptyBody::Drop => Action::delete(DropReason::EmptyBody),
+                EmptyBody::Folder(name) => Action::folder(
+                    folders
                         .iter()
-                        .chain(new.parsed.headers.get_all_values("cc").iter())
-                        .any(|recip| recip.contains(&ignore.name))
-            })
-            .unwrap_or(false)
-    {
+                        .position(|f| f.name == *name)
+                        .ok_or(Error::Internal)?,
+                ),
+            }
+        }
+        (None, _) => cfg
+            .auto_list_folders
+            .then(|| list_folder(new))
+            .flatten()
+            .filter(|_| ignored(new, cfg).is_none())
+            .and_then(|name| folders.iter().position(|f| f.name == name))
+            .map_or_else(
+                || match cfg.unmatched {
+                    Unmatched::Inbox => Action::folder(rest),
+                    Unmatched::Drop => Action::delete(DropReason::Unmatched),
+                },
+                |i| {
+                    reason = Reason::List;
+                    Action::folder(i)
+                },
+            ),
+    };
+
+    if reason == Reason::Default {
+        plan.unmatched.push(new.clone());
+    }
+
+    if action.folder_idx() == Some(rest) && !action.is_flagged() && ignored(new, cfg).is_some() {
         action = Action::delete(DropReason::Ignored);
     }
 
-    compute_flags(new, &mut action, folders, cfg)?;
+    if let Dest::Folder(primary) = action.dest() {
+        let copies = folders
+            .iter()
+            .enumerate()
+            .filter(|(i, f)| {
+                *i != primary && (cfg.cross_file || f.copy) && f.matches(&fields).is_some()
+            })
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if !copies.is_empty() {
+            plan.copies.insert(new.clone(), copies);
+        }
+    }
+
+    if !ctx.headers_only {
+        compute_flags(new, &body, &mut action, folders, cfg)?;
+    }
+
+    if from_self(new, cfg) {
+        action.read();
+    } else if let (Some(parent), Dest::Folder(_)) = (stored_parent, action.dest()) {
+        match cfg.resurface_threads {
+            Some(Resurface::Unread) if parent.flags.contains('S') => {
+                plan.unread.insert(parent);
+            }
+            Some(Resurface::Flag) => action.flag(),
+            _ => {}
+        }
+    }
+    if cfg.status_headers {
+        let headers = &new.parsed.headers;
+        let status = headers.get_first_value("Status").unwrap_or_default();
+        let x_status = headers.get_first_value("X-Status").unwrap_or_default();
+        action.add_flags(
+            status.contains('R'),
+            x_status.contains('F'),
+            x_status.contains('A'),
+        );
+    }
+    plan.actions.insert(new.clone(), action);
+    plan.reasons.insert(new.clone(), reason);
+    Ok(action)
+}
+
+/// Finds a stored mail with the same subject as the parentless `mail`.
+///
+/// To avoid false positives, `mail` also needs to reference the stored mail or one of the mails
+/// it references.
+fn subject_parent<'c, 'a>(mail: &Mail<'a>, ctx: &Context<'a, 'c>) -> Option<&'c Rc<Mail<'a>>> {
+    if ctx.oversized.contains(&mail.id) || mail.references.is_empty() {
+        return None;
+    }
+    let subject = mail.subject()?;
+    ctx.subjects
+        .get(mail::normalize_subject(&subject))?
+        .iter()
+        .find(|stored| {
+            mail.references.contains(&stored.id)
+                || stored
+                    .references
+                    .iter()
+                    .any(|r| mail.references.contains(r))
+        })
+}
 
-    if new
+/// Returns why `mail` should be ignored: its `List-Id` or a matching subject pattern.
+///
+/// Mails that [mention](mentions) the user are never ignored.
+fn ignored(mail: &Mail<'_>, cfg: &Config) -> Option<String> {
+    let ignore = cfg.ignore.as_ref()?;
+    if mentions(mail, cfg) {
+        return None;
+    }
+    if let Some(list) = mail
         .parsed
         .headers
-        .get_all_values("from")
-        .iter()
-        .any(|f| cfg.addresses.iter().any(|addr| f.contains(addr)))
+        .get_all_values("List-Id")
+        .into_iter()
+        .find(|id| ignore.lists.contains(id))
     {
-        action.read();
+        return Some(format!("list {list}"));
     }
-    actions.insert(new.clone(), action);
-    Ok(action)
+    let subject = mail.subject().unwrap_or_default();
+    ignore
+        .subject_patterns
+        .iter()
+        .find(|kw| kw.matches(&subject))
+        .map(|kw| format!("subject pattern `{kw}`"))
+}
+
+/// Returns whether `mail` was sent from one of the user's own [`Config::addresses`].
+fn from_self(mail: &Mail<'_>, cfg: &Config) -> bool {
+    mail.author_headers(cfg).iter().any(|f| {
+        cfg.addresses
+            .iter()
+            .any(|addr| f.get_value().contains(addr))
+    })
 }
 
+/// Returns whether `mail` is directly sent to the configured
+/// [`Ignore::name`](crate::config::Ignore::name).
+fn mentions(mail: &Mail<'_>, cfg: &Config) -> bool {
+    let Some(ignore) = &cfg.ignore else {
+        return false;
+    };
+    let headers = &mail.parsed.headers;
+    headers
+        .get_all_values("to")
+        .iter()
+        .chain(headers.get_all_values("cc").iter())
+        .any(|recip| recip.contains(&ignore.name))
+}
+
+/// Sets the flags of `action` that depend on its folder, `body` is the [`Mail::body`] of `mail`.
 fn compute_flags<'a>(
     mail: &Rc<Mail<'a>>,
+    body: &str,
     action: &mut Action,
     folders: &[Folder],
     cfg: &Config,
 ) -> Result<(), Error> {
     match action.dest() {
-        Dest::Drop(_) => {}
+        Dest::Drop(_) | Dest::Quarantine => {}
         Dest::Folder(i) => {
-            let body = mail.parsed.get_body()?;
-            if folders[i].mark_read {
+            let read_list = mail
+                .parsed
+                .headers
+                .get_all_values("list-id")
+                .iter()
+                .any(|id| cfg.mark_read_lists.contains(id));
+            let mark_read = (folders[i].mark_read || read_list)
+                && !(folders[i].unread_on_mention && mentions(mail, cfg));
+            if mark_read || folders[i].digest == Some(Digest::Only) {
                 action.read();
             }
+            action.set_flags(&folders[i].set_flags);
             if let Some(fkws) = &folders[i].flagging_keywords {
-                if fkws.iter().any(|kw| kw.matches(&body)) {
+                if fkws.iter().any(|kw| kw.matches(body)) {
                     action.flag();
                 }
-            } else if cfg.flagging.keywords.iter().any(|kw| kw.matches(&body)) {
+            } else if cfg.flagging.keywords.iter().any(|kw| kw.matches(body)) {
                 action.flag();
             }
         }
@@ -340,97 +1279,577 @@ fn compute_flags<'a>(
     Ok(())
 }
 
+/// Moves `mail` to `dest` and recomputes the flags that depend on the folder.
+///
+/// Mails from the user's own [`Config::addresses`] stay read.
+fn refile<'a>(
+    mail: &Rc<Mail<'a>>,
+    action: &mut Action,
+    dest: Dest,
+    ctx: &Context<'a, '_>,
+) -> Result<(), Error> {
+    *action = action.with_cleared_flags();
+    action.set_dest(dest);
+    if !ctx.headers_only {
+        compute_flags(mail, &mail.body(ctx.cfg)?, action, ctx.folders, ctx.cfg)?;
+    }
+    if from_self(mail, ctx.cfg) {
+        action.read();
+    }
+    Ok(())
+}
+
+/// Delivers all new mails of a thread into the same folder.
+///
+/// The threads are the connected components of the `In-Reply-To` and `References` links between
+/// the new mails, so mails whose common ancestor was not downloaded are also grouped. A thread
+/// goes into the folder of its mail threaded to a stored mail, or else into the highest priority
+/// folder any of its mails was assigned to. Deleted mails stay deleted.
+fn unify_threads<'a>(
+    new: &[Rc<Mail<'a>>],
+    ctx: &Context<'a, '_>,
+    plan: &mut Plan<'a>,
+) -> Result<(), Error> {
+    fn find(roots: &mut [usize], mut i: usize) -> usize {
+        while roots[i] != i {
+            roots[i] = roots[roots[i]];
+            i = roots[i];
+        }
+        i
+    }
+    let mut index = HashMap::with_capacity(new.len());
+    for (i, mail) in new.iter().enumerate() {
+        index.entry(mail.id.as_str()).or_insert(i);
+    }
+    let mut roots = (0..new.len()).collect::<Vec<_>>();
+    for (i, mail) in new.iter().enumerate() {
+        if ctx.oversized.contains(&mail.id) {
+            continue;
+        }
+        for id in mail.parent.iter().chain(&mail.references) {
+            if let Some(&j) = index.get(id.as_str()) {
+                let (a, b) = (find(&mut roots, i), find(&mut roots, j));
+                roots[a] = b;
+            }
+        }
+    }
+    let mut threads = HashMap::<usize, Vec<&Rc<Mail<'a>>>>::new();
+    for (i, mail) in new.iter().enumerate() {
+        if plan.actions.contains_key(mail) {
+            threads.entry(find(&mut roots, i)).or_default().push(mail);
+        }
+    }
+    for thread in threads.values().filter(|thread| thread.len() > 1) {
+        // mails threaded to a stored mail have to stay in its folder, threads pinned to multiple
+        // folders are left to `fixup_thread_siblings`.
+        let pinned = thread
+            .iter()
+            .filter(|mail| {
+                ctx.stored_threads()
+                    && mail
+                        .parent
+                        .as_ref()
+                        .and_then(|parent| ctx.indexed.get(parent))
+                        .is_some_and(|parents| parents.iter().any(|p| p.typ != Type::New))
+            })
+            .collect::<Vec<_>>();
+        let mut pins = pinned.iter().map(|mail| plan.actions[**mail].folder_idx());
+        let dest = match pins.next() {
+            Some(first) if pins.all(|pin| pin == first) => first,
+            Some(_) => continue,
+            None => thread
+                .iter()
+                .filter_map(|mail| plan.actions[*mail].folder_idx())
+                .min(),
+        };
+        let Some(dest) = dest else {
+            continue;
+        };
+        for mail in thread.iter().filter(|mail| !pinned.contains(mail)) {
+            let action = plan.actions.get_mut(*mail).ok_or(Error::Internal)?;
+            if action.folder_idx().is_some_and(|i| i != dest) {
+                refile(mail, action, Dest::Folder(dest), ctx)?;
+                plan.reasons.insert((*mail).clone(), Reason::Thread);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[instrument(skip_all, fields(mails = new.len(), rounds = field::Empty, moved = field::Empty))]
 fn fixup_thread_siblings<'a>(
     new: &[Rc<Mail<'a>>],
-    indexed: &HashMap<String, Vec<Rc<Mail<'a>>>>,
-    actions: &mut HashMap<Rc<Mail<'a>>, Action>,
-    folders: &[Folder],
-    cfg: &Config,
+    ctx: &Context<'a, '_>,
+    plan: &mut Plan<'a>,
 ) -> Result<(), Error> {
-    let mut error = false;
+    let Plan {
+        actions,
+        reasons,
+        copies,
+        ..
+    } = plan;
+    let Context { indexed, cfg, .. } = *ctx;
+    let mut offending = Vec::new();
     let mut changed = true;
+    let (mut rounds, mut moved) = (0, 0);
     while changed {
         changed = false;
+        rounds += 1;
         for new in new {
-            if let Some(parent) = &new.parent {
-                if let Some(parents) = indexed.get(parent) {
-                    for parent in parents {
-                        if parent.typ == Type::New {
-                            let ours = actions[new].dest();
-                            let theirs = actions[parent].dest();
-                            if ours != theirs {
-                                if let Some(dest) = Dest::max_prio(ours, theirs) {
-                                    let ours = actions.get_mut(new).unwrap();
-                                    ours.set_dest(dest);
-                                    compute_flags(new, ours, folders, cfg)?;
-                                    let theirs = actions.get_mut(parent).unwrap();
-                                    theirs.set_dest(dest);
-                                    compute_flags(new, theirs, folders, cfg)?;
-                                    changed = true;
-                                }
-                            }
+            if ctx.oversized.contains(&new.id) {
+                continue;
+            }
+            let Some(parents) = new.parent.as_ref().and_then(|parent| indexed.get(parent)) else {
+                continue;
+            };
+            for parent in parents.iter().filter(|parent| parent.typ == Type::New) {
+                let ours = actions[new].dest();
+                let theirs = actions[parent].dest();
+                if ours != theirs {
+                    if let Some(dest) = Dest::max_prio(ours, theirs) {
+                        let (changed_mail, action) = if ours == dest {
+                            (parent, actions.get_mut(parent).unwrap())
                         } else {
-                            let action = actions[new];
-                            if let Some(typ) = Option::<Type>::from(action.dest()) {
-                                if typ != parent.typ {
-                                    error = true;
-                                    error!(
-                                        "moved into wrong folder with parent!\n\t{} ({:?})\n\t{} -> {:?}",
-                                        parent.path.display(),
-                                        parent.typ,
-                                        new.path.display(),
-                                        action
-                                    )
-                                }
-                            }
-                        }
+                            (new, actions.get_mut(new).unwrap())
+                        };
+                        refile(changed_mail, action, dest, ctx)?;
+                        reasons.insert(changed_mail.clone(), Reason::Thread);
+                        changed = true;
+                        moved += 1;
                     }
                 }
             }
+            // with copies, the parent and the reply can be stored in multiple folders, they only
+            // have to share one of them.
+            let mut stored = parents.iter().filter(|parent| parent.typ != Type::New);
+            let action = actions[new];
+            let Some(typ) = Option::<Type>::from(action.dest()) else {
+                continue;
+            };
+            let Some(parent) = stored.clone().next().filter(|_| ctx.stored_threads()) else {
+                continue;
+            };
+            let ours = copies.get(new).map_or(&[][..], Vec::as_slice);
+            if stored.any(|parent| {
+                parent.typ == typ || ours.iter().any(|&i| parent.typ == Type::Folder(i))
+            }) {
+                continue;
+            }
+            if ctx.skip_errors {
+                warn!(
+                    "`{}` would not be stored with its parent, quarantining it",
+                    new.path.display()
+                );
+                actions.insert(new.clone(), Action::quarantine());
+            } else {
+                error!(
+                    "moved into wrong folder with parent!\n\t{} ({:?})\n\t{} -> {:?}",
+                    parent.path.display(),
+                    parent.typ,
+                    new.path.display(),
+                    action
+                );
+                offending.extend([parent.path.clone(), new.path.clone()]);
+            }
         }
     }
-    if error {
+    Span::current()
+        .record("rounds", rounds)
+        .record("moved", moved);
+    if !offending.is_empty() {
         eprintln!("An error occurred with wanting to move emails into separate folders above.");
         eprintln!("If you report the error, please include the offending email files.");
         eprintln!();
-        eprintln!("Press enter to terminate the program & delete the temporary directory.");
+        wait_for_user(cfg, &offending);
+        return Err(Error::Internal);
+    }
+    Ok(())
+}
+
+/// Gives the user time to inspect the temporary directory before it is deleted.
+///
+/// Waits at most [`Config::error_prompt_timeout`] seconds, if it is set, and not at all if stdin
+/// is not a terminal. If the timeout passes, the `offending` mails are archived with
+/// [`archive_offending`].
+fn wait_for_user(cfg: &Config, offending: &[PathBuf]) {
+    if !io::stdin().is_terminal() {
+        return;
+    }
+    eprintln!("Press enter to terminate the program & delete the temporary directory.");
+    let Some(timeout) = cfg.error_prompt_timeout else {
         io::stdin()
             .read_line(&mut String::new())
             .expect("failed to read from stdin");
-        return Err(Error::Internal);
+        return;
+    };
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || tx.send(io::stdin().read_line(&mut String::new())));
+    match rx.recv_timeout(Duration::from_secs(timeout)) {
+        Ok(res) => {
+            res.expect("failed to read from stdin");
+        }
+        Err(_) => {
+            eprintln!("No input after {timeout} seconds, terminating.");
+            archive_offending(offending);
+        }
     }
-    Ok(())
 }
 
-fn perform<'a>(actions: HashMap<Rc<Mail<'a>>, Action>, folders: &[Folder]) -> Result<(), Error> {
+/// Copies the `offending` mails into a new directory in `~/.cache/lkml/errors`, so they are still
+/// around for a bug report after the temporary directory is deleted.
+fn archive_offending(offending: &[PathBuf]) {
+    let Some(dirs) = BaseDirs::new() else {
+        warn!("unable to locate the home directory, not archiving the offending mails");
+        return;
+    };
+    let now = chrono::Local::now().format("%Y-%m-%d-%H%M%S").to_string();
+    let dir = dirs.cache_dir().join("lkml").join("errors").join(now);
+    let res = fs::create_dir_all(&dir).and_then(|()| {
+        offending.iter().try_for_each(|path| {
+            let name = path.file_name().unwrap_or_default();
+            fs::copy(path, dir.join(name)).map(|_| ())
+        })
+    });
+    match res {
+        Ok(()) => eprintln!(
+            "The offending email files were copied to `{}`.",
+            dir.display()
+        ),
+        Err(e) => warn!(
+            "could not archive the offending mails in `{}`: {e}",
+            dir.display()
+        ),
+    }
+}
+
+/// Appends the subject and the first `lines` lines of the body of the unmatched mails that still
+/// go into the `rest` folder to the file at `path`.
+fn dump_unmatched(plan: &Plan<'_>, rest: usize, path: &Path, lines: usize) -> Result<(), Error> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(Error::Fs)?;
+    let mut file = BufWriter::new(file);
+    for mail in &plan.unmatched {
+        if plan.actions.get(mail).and_then(Action::folder_idx) != Some(rest) {
+            continue;
+        }
+        let subject = mail.subject();
+        let body = mail.parsed.get_body()?;
+        writeln!(file, "=== {} ===", mail.id).map_err(Error::Fs)?;
+        writeln!(file, "Subject: {}", subject.unwrap_or_default()).map_err(Error::Fs)?;
+        for line in body.lines().take(lines) {
+            writeln!(file, "{line}").map_err(Error::Fs)?;
+        }
+        writeln!(file).map_err(Error::Fs)?;
+    }
+    file.flush().map_err(Error::Fs)
+}
+
+#[cfg(unix)]
+const INFORMATIONAL_SUFFIX_SEPARATOR: &str = ":";
+#[cfg(windows)]
+const INFORMATIONAL_SUFFIX_SEPARATOR: &str = ";";
+
+/// Returns the file name of the mail with the maildir `id`, `flags` and, if known, `size`.
+///
+/// With [`MaildirDialect::Dovecot`], the size is added to the unique part of the name as dovecot
+/// does.
+fn file_name(id: &str, flags: &str, size: Option<usize>, cfg: &Config) -> String {
+    match (cfg.maildir_dialect, size) {
+        (MaildirDialect::Dovecot, Some(size)) if !id.contains(",S=") => {
+            format!("{id},S={size}{INFORMATIONAL_SUFFIX_SEPARATOR}2,{flags}")
+        }
+        _ => format!("{id}{INFORMATIONAL_SUFFIX_SEPARATOR}2,{flags}"),
+    }
+}
+
+/// A new mail delivered to a folder, see [`Deliver`].
+struct Delivery<'m, 'a> {
+    mail: &'m Mail<'a>,
+    action: Action,
+    /// The flags of the [`Delivery::action`] merged with the existing ones of the mail.
+    flags: &'m str,
+    /// The index of the folder the mail goes into.
+    folder: usize,
+    /// Additional folders to put a copy of the mail into, might contain [`Delivery::folder`].
+    copies: &'m [usize],
+    /// Why the mail goes into [`Delivery::folder`].
+    reason: &'m Reason,
+}
+
+/// The way mails are delivered into the store by [`perform`].
+trait Deliver {
+    /// Delivers a single mail, which is moved out of the new mails.
+    fn deliver(&mut self, delivery: &Delivery<'_, '_>) -> Result<(), Error>;
+
+    /// Moves a mail that is inconsistent with the store into the quarantine folder `into`.
+    fn quarantine(&mut self, mail: &Mail<'_>, flags: &str, into: &Folder) -> Result<(), Error>;
+
+    /// Finishes the delivery after all mails were passed to [`Deliver::deliver`].
+    fn finish(self: Box<Self>) -> Result<(), Error>;
+}
+
+/// Delivers mails by moving them into the maildirs of their folders.
+struct MaildirStore<'f> {
+    folders: &'f [Folder],
+    cfg: &'f Config,
+}
+
+impl Deliver for MaildirStore<'_> {
+    fn deliver(&mut self, delivery: &Delivery<'_, '_>) -> Result<(), Error> {
+        let Delivery {
+            mail,
+            flags,
+            folder,
+            copies,
+            reason,
+            ..
+        } = *delivery;
+        let id = &mail.maildir_id;
+        for &copy in copies.iter().filter(|&&copy| copy != folder) {
+            let dst = store_file(mail, flags, &self.folders[copy], "copy", false, self.cfg)?;
+            info!("copying `{id}` to {} ({flags})", dst.display());
+        }
+        let dst = store_file(mail, flags, &self.folders[folder], reason, true, self.cfg)?;
+        info!(
+            "moving `{id}` to {} ({flags}) [{} -> {}]",
+            self.folders[folder].maildir.path().display(),
+            mail.path.display(),
+            dst.display()
+        );
+        Ok(())
+    }
+
+    fn quarantine(&mut self, mail: &Mail<'_>, flags: &str, into: &Folder) -> Result<(), Error> {
+        let dst = store_file(mail, flags, into, "quarantined", true, self.cfg)?;
+        warn!("quarantined `{}` in `{}`", mail.maildir_id, dst.display());
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Writes `mail` with `flags` into `folder` and returns its new path.
+///
+/// With `take`, the file of the mail is moved instead of copied. The [`Config::tag_header`] names
+/// `folder` with `reason`.
+fn store_file(
+    mail: &Mail<'_>,
+    flags: &str,
+    folder: &Folder,
+    reason: impl fmt::Display,
+    take: bool,
+    cfg: &Config,
+) -> Result<PathBuf, Error> {
+    let src = &mail.path;
+    let mut headers = Vec::new();
+    if cfg.tag_header {
+        let tag = format!("{} ({reason})", folder.name);
+        headers.push(("X-Lkml-Folder", tag));
+    }
+    if cfg.archive_header {
+        headers.push(("X-Lkml-Archive-URL", mail.archive_url(&cfg.inbox)));
+    }
+    let raw = (!headers.is_empty()).then(|| {
+        let mut raw = mail.parsed.raw_bytes.to_vec();
+        for (name, value) in headers {
+            raw = mail::insert_header(&raw, name, &value);
+        }
+        raw
+    });
+    let size = raw.as_ref().map_or(mail.parsed.raw_bytes.len(), Vec::len);
+    let name = file_name(&mail.maildir_id, flags, Some(size), cfg);
+    let dst = folder.maildir.path().join("cur").join(name);
+    match (&raw, take) {
+        (Some(raw), false) => std::fs::write(&dst, raw),
+        (Some(raw), true) => std::fs::write(&dst, raw).and_then(|()| std::fs::remove_file(src)),
+        (None, true) => move_file(src, &dst),
+        (None, false) => std::fs::copy(src, &dst).map(drop),
+    }
+    .map_err(Error::Fs)?;
+    Ok(dst)
+}
+
+/// Moves, copies and deletes the mails according to `plan`.
+///
+/// With [`Options::dry_run`], the actions are only printed. With [`Config::notmuch`], all mails are
+/// stored in the `rest` folder and tagged with the names of their folders instead.
+#[instrument(
+    skip_all,
+    fields(actions = plan.actions.len(), delivered = field::Empty, deleted = field::Empty)
+)]
+fn perform(
+    plan: Plan<'_>,
+    folders: &[Folder],
+    rest: usize,
+    cfg: &Config,
+    opts: Options<'_>,
+) -> Result<Summary, Error> {
+    let dry_run = opts.dry_run;
+    let mut summary = Summary::default();
+    let mut digests = BTreeMap::<usize, Vec<Rc<Mail<'_>>>>::new();
+    let mut backend: Box<dyn Deliver> = match &cfg.notmuch {
+        Some(notmuch) => Box::new(notmuch::Tagger::new(notmuch, folders, rest, cfg)),
+        None => Box::new(MaildirStore { folders, cfg }),
+    };
+    let Plan {
+        actions,
+        reasons,
+        copies,
+        unread,
+        unmatched: _,
+    } = plan;
+    // mails that are moved or deleted get their flags from their action.
+    let unread = unread
+        .into_iter()
+        .filter(|mail| !actions.contains_key(mail))
+        .collect::<Vec<_>>();
     for (mail, action) in actions {
         let id = &mail.maildir_id;
-        let flags = action.flags();
-        let dest = match action.dest() {
-            Dest::Drop(_) => {
+        let flags = action.merged_flags(&mail.flags);
+        let duplicate = matches!(
+            action.dest(),
+            Dest::Drop(DropReason::VerbatimCopy | DropReason::DuplicateQuirk)
+        );
+        summary.new += usize::from(!duplicate);
+        summary.read += usize::from(action.is_read());
+        summary.flagged += usize::from(action.is_flagged());
+        let idx = match action.dest() {
+            Dest::Drop(reason) if dry_run => {
+                println!("would delete `{id}` ({reason})");
+                *summary.dropped.entry(reason).or_default() += 1;
+                continue;
+            }
+            Dest::Quarantine => {
+                let name = cfg.quarantine();
+                if dry_run {
+                    println!("would quarantine `{id}` in {name}");
+                    summary.quarantined += 1;
+                    continue;
+                }
+                let unconfigured;
+                let quarantine = match folders.iter().find(|f| f.name == name) {
+                    Some(folder) => folder,
+                    None => {
+                        unconfigured = Folder::unconfigured(name, folders[rest].maildir.path());
+                        &unconfigured
+                    }
+                };
+                quarantine.maildir.create_dirs().map_err(Error::Fs)?;
+                backend.quarantine(&mail, &flags, quarantine)?;
+                summary.quarantined += 1;
+                continue;
+            }
+            Dest::Drop(reason) => {
                 std::fs::remove_file(&mail.path).map_err(Error::Fs)?;
-                info!("deleting `{id}`");
+                info!("deleting `{id}` ({reason})");
+                *summary.dropped.entry(reason).or_default() += 1;
                 continue;
             }
-            Dest::Folder(idx) => &folders[idx].maildir,
+            Dest::Folder(idx) => idx,
         };
-        let src = &mail.path;
-        #[cfg(unix)]
-        const INFORMATIONAL_SUFFIX_SEPARATOR: &str = ":";
-        #[cfg(windows)]
-        const INFORMATIONAL_SUFFIX_SEPARATOR: &str = ";";
-        let dst = dest
-            .path()
-            .join("cur")
-            .join(format!("{id}{INFORMATIONAL_SUFFIX_SEPARATOR}2,{flags}"));
+        let reason = reasons.get(&mail).unwrap_or(&Reason::Default);
+        if dry_run {
+            println!(
+                "would move `{id}` to {} ({reason}) with flags `{flags}`",
+                folders[idx].name
+            );
+            *summary
+                .delivered
+                .entry(folders[idx].name.clone())
+                .or_default() += 1;
+            for &copy in copies.get(&mail).into_iter().flatten() {
+                if copy != idx {
+                    println!("would copy `{id}` to {}", folders[copy].name);
+                    *summary
+                        .delivered
+                        .entry(folders[copy].name.clone())
+                        .or_default() += 1;
+                }
+            }
+            continue;
+        }
+        if folders[idx].digest.is_some() && !opts.reassort {
+            digests.entry(idx).or_default().push(mail.clone());
+        }
+        let copies = copies.get(&mail).map_or(&[][..], Vec::as_slice);
+        backend.deliver(&Delivery {
+            mail: &mail,
+            action,
+            flags: &flags,
+            folder: idx,
+            copies,
+            reason,
+        })?;
+        for &folder in std::iter::once(&idx).chain(copies.iter().filter(|&&copy| copy != idx)) {
+            *summary
+                .delivered
+                .entry(folders[folder].name.clone())
+                .or_default() += 1;
+        }
+    }
+    backend.finish()?;
+    for mail in unread {
+        let id = &mail.maildir_id;
+        let flags = mail.flags.replace('S', "");
+        if dry_run {
+            println!("would mark `{id}` as unread");
+            continue;
+        }
+        let dst = mail.path.with_file_name(file_name(id, &flags, None, cfg));
+        info!("marking `{id}` as unread ({flags})");
+        std::fs::rename(&mail.path, dst).map_err(Error::Fs)?;
+    }
+    for (idx, mails) in digests {
+        let folder = &folders[idx];
+        let id = folder
+            .maildir
+            .store_cur_with_flags(digest(&folder.name, &mails, &cfg.inbox).as_bytes(), "")?;
         info!(
-            "moving `{id}` to {} ({flags}) [{} -> {}]",
-            dest.path().display(),
-            src.display(),
-            dst.display()
+            "delivering digest `{id}` of {} mail(s) to {}",
+            mails.len(),
+            folder.name
         );
-        std::fs::copy(src, dst).map_err(Error::Fs)?;
-        std::fs::remove_file(src).map_err(Error::Fs)?;
     }
-    Ok(())
+    Span::current()
+        .record("delivered", summary.delivered.values().sum::<usize>())
+        .record("deleted", summary.dropped.values().sum::<usize>());
+    Ok(summary)
+}
+
+/// Start of the `Message-ID` of the digest mails.
+const DIGEST_ID: &str = "<lkml-digest.";
+
+/// Builds the digest mail of the new `mails` in `folder`.
+fn digest(folder: &str, mails: &[Rc<Mail<'_>>], inbox: &str) -> String {
+    let now = chrono::Local::now();
+    let mut digest = format!(
+        "From: lkml <lkml@localhost>\n\
+         Subject: [lkml digest] {folder}: {} new mail(s)\n\
+         Date: {}\n\
+         Message-ID: {DIGEST_ID}{}.{folder}@localhost>\n\
+         MIME-Version: 1.0\n\
+         Content-Type: text/plain; charset=utf-8\n\
+         Content-Transfer-Encoding: 8bit\n\n",
+        mails.len(),
+        now.to_rfc2822(),
+        now.timestamp_millis(),
+    );
+    for mail in mails {
+        let subject = mail.subject().unwrap_or_default();
+        let from = mail
+            .parsed
+            .headers
+            .get_first_value("From")
+            .unwrap_or_default();
+        digest.push_str(&format!(
+            "- {subject}\n  {from}\n  {}\n\n",
+            mail.archive_url(inbox)
+        ));
+    }
+    digest
 }
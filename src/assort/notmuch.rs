@@ -0,0 +1,153 @@
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::{
+    assort::{self, Deliver, Delivery, folder::Folder, mail::Mail, store_file},
+    config::{self, Config},
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("could not execute `notmuch`: {0}")]
+    Start(#[from] io::Error),
+    #[error("`notmuch` failed execution with error code {0}")]
+    Code(i32),
+    #[error("`notmuch` execution unexpectedly terminated by signal.")]
+    Signal,
+}
+
+type Result<T = ()> = core::result::Result<T, Error>;
+
+fn notmuch(cfg: &config::Notmuch) -> Command {
+    let mut cmd = Command::new("notmuch");
+    if let Some(config) = &cfg.config {
+        cmd.arg(format!("--config={}", config.display()));
+    }
+    cmd
+}
+
+fn check(res: std::process::ExitStatus) -> Result {
+    if res.success() {
+        Ok(())
+    } else {
+        Err(res.code().map(Error::Code).unwrap_or(Error::Signal))
+    }
+}
+
+/// Delivers mails into the root maildir and tags them with the names of their folders.
+pub struct Tagger<'f> {
+    notmuch: &'f config::Notmuch,
+    folders: &'f [Folder],
+    rest: usize,
+    cfg: &'f Config,
+    /// The `Message-ID` and tag operations of every delivered mail.
+    tags: Vec<(String, Vec<String>)>,
+}
+
+impl<'f> Tagger<'f> {
+    pub fn new(
+        notmuch: &'f config::Notmuch,
+        folders: &'f [Folder],
+        rest: usize,
+        cfg: &'f Config,
+    ) -> Self {
+        Self {
+            notmuch,
+            folders,
+            rest,
+            cfg,
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl Deliver for Tagger<'_> {
+    fn deliver(&mut self, delivery: &Delivery<'_, '_>) -> core::result::Result<(), assort::Error> {
+        let Delivery {
+            mail,
+            action,
+            flags,
+            folder,
+            copies,
+            reason,
+        } = *delivery;
+        let mut ops = vec![format!("+{}", encode(&self.folders[folder].name))];
+        for &copy in copies {
+            ops.push(format!("+{}", encode(&self.folders[copy].name)));
+        }
+        if action.is_read() {
+            ops.push("-unread".to_owned());
+        }
+        if action.is_flagged() {
+            ops.push("+flagged".to_owned());
+        }
+        store_file(
+            mail,
+            flags,
+            &self.folders[self.rest],
+            reason,
+            true,
+            self.cfg,
+        )?;
+        info!("tagging `{}` with {}", mail.maildir_id, ops.join(" "));
+        self.tags.push((mail.id.clone(), ops));
+        Ok(())
+    }
+
+    fn quarantine(
+        &mut self,
+        mail: &Mail<'_>,
+        flags: &str,
+        into: &Folder,
+    ) -> core::result::Result<(), assort::Error> {
+        // kept out of the root maildir, which is indexed as part of the store.
+        let dst = store_file(mail, flags, into, "quarantined", true, self.cfg)?;
+        warn!("quarantined `{}` in `{}`", mail.maildir_id, dst.display());
+        self.tags
+            .push((mail.id.clone(), vec![format!("+{}", encode(&into.name))]));
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> core::result::Result<(), assort::Error> {
+        if !self.tags.is_empty() {
+            tag(self.notmuch, &self.tags)?;
+        }
+        Ok(())
+    }
+}
+
+/// Indexes the new mails and applies `tags` to them.
+///
+/// `tags` contains the `Message-ID` (including `<>`) and tag operations like `+rust` or `-unread`
+/// of every mail.
+pub fn tag(cfg: &config::Notmuch, tags: &[(String, Vec<String>)]) -> Result {
+    check(notmuch(cfg).arg("new").arg("--quiet").status()?)?;
+    let mut child = notmuch(cfg)
+        .args(["tag", "--batch"])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    for (id, ops) in tags {
+        let id = id.trim_start_matches('<').trim_end_matches('>');
+        writeln!(stdin, "{} -- id:{}", ops.join(" "), encode(id))?;
+    }
+    drop(stdin);
+    check(child.wait()?)
+}
+
+/// Encodes the characters that have a special meaning in `notmuch tag --batch` input.
+fn encode(term: &str) -> String {
+    let mut res = String::with_capacity(term.len());
+    for c in term.chars() {
+        match c {
+            ' ' | '%' | '(' | ')' | '"' => res.push_str(&format!("%{:02x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res
+}
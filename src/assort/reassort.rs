@@ -0,0 +1,87 @@
+use std::{fs, io, path::Path};
+
+use maildir::Maildir;
+use mailparse::MailHeaderMap;
+use tracing::{error, warn};
+
+use crate::{
+    assort::{DIGEST_ID, Error, Options, Summary, folders, reassorted, run},
+    config::Config,
+};
+
+/// Assorts the mails stored in the configured folders and the `INBOX` again with the current
+/// rules, moving them into the folders they belong into now.
+///
+/// The mails are moved into the maildir `.lkml-reassort` of the store and assorted from there
+/// like newly downloaded ones, so they can also be deleted (e.g. when they are ignored now). Mails
+/// in archive, list and other unconfigured folders stay where they are, but replies still follow
+/// them. Digests also stay and no new ones are delivered. Without `confirm`, the mails are hard
+/// linked instead and only the planned changes are printed.
+pub fn reassort(
+    main: Maildir,
+    cfg: &Config,
+    opts: Options<'_>,
+    confirm: bool,
+) -> Result<Summary, Error> {
+    let root = main.path().to_owned();
+    let staging = Maildir::from(root.join(".lkml-reassort"));
+    if staging.path().exists() {
+        return Err(Error::Reassort(staging.path().to_owned()));
+    }
+    staging.create_dirs().map_err(Error::Fs)?;
+    let (folders, _) = folders(Maildir::from(root.clone()), cfg);
+    for folder in folders.iter().filter(|f| reassorted(f, cfg)) {
+        for entry in folder.maildir.list_new().chain(folder.maildir.list_cur()) {
+            let mut entry = entry.map_err(Error::MailIO)?;
+            let digest = entry
+                .headers()?
+                .get_first_value("Message-ID")
+                .is_some_and(|id| id.trim().starts_with(DIGEST_ID));
+            if digest {
+                continue;
+            }
+            let Some(name) = entry.path().file_name() else {
+                continue;
+            };
+            let dst = staging.path().join("cur").join(name);
+            if dst.exists() {
+                // only possible for copies of the same mail with `files_copies`.
+                warn!(
+                    "`{}` is stored multiple times, keeping it",
+                    entry.path().display()
+                );
+                continue;
+            }
+            stage(entry.path(), &dst, confirm).map_err(Error::Fs)?;
+        }
+    }
+    let opts = Options {
+        reassort: true,
+        dry_run: !confirm,
+        ..opts
+    };
+    let summary = run(
+        Maildir::from(staging.path().to_owned()),
+        Maildir::from(root),
+        cfg,
+        opts,
+    );
+    if confirm && summary.is_err() {
+        error!(
+            "the mails that were not reassorted are in `{0}`, assort them with `lkml assort {0}`",
+            staging.path().display()
+        );
+        return summary;
+    }
+    fs::remove_dir_all(staging.path()).map_err(Error::Fs)?;
+    summary
+}
+
+/// Moves the mail `from` to `to`, or hard links it there if the mails are not moved for real.
+fn stage(from: &Path, to: &Path, confirm: bool) -> io::Result<()> {
+    if confirm {
+        fs::rename(from, to)
+    } else {
+        fs::hard_link(from, to)
+    }
+}
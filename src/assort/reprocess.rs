@@ -0,0 +1,58 @@
+use std::{fs, rc::Rc};
+
+use maildir::Maildir;
+use tracing::info;
+
+use crate::{
+    assort::{
+        Error, compute_flags, file_name,
+        folder::Action,
+        folders,
+        mail::{self, Type},
+    },
+    config::Config,
+};
+
+/// Recomputes the `Flagged` flag of the mails stored in the folders called `names`.
+///
+/// Mails are never moved to another folder and their other flags are kept. Returns the number of
+/// mails whose flags changed.
+pub fn reprocess(main: Maildir, cfg: &Config, names: &[String]) -> Result<usize, Error> {
+    let (folders, _) = folders(main, cfg);
+    let mut changed = 0;
+    for name in names {
+        let i = folders
+            .iter()
+            .position(|f| f.name == *name)
+            .ok_or_else(|| Error::UnknownFolder(name.clone()))?;
+        let maildir = &folders[i].maildir;
+        for entry in maildir.list_new().chain(maildir.list_cur()) {
+            let mut entry = entry.map_err(Error::MailIO)?;
+            let mail = Rc::new(mail::parse(&mut entry, Type::Folder(i), cfg)?);
+            let mut action = Action::folder(i);
+            compute_flags(&mail, &mail.body(cfg)?, &mut action, &folders, cfg)?;
+            let mut flags = mail.flags.replace('F', "");
+            if action.is_flagged() {
+                flags.push('F');
+            }
+            let mut flags = flags.chars().collect::<Vec<_>>();
+            flags.sort_unstable();
+            let flags = flags.into_iter().collect::<String>();
+            if flags == mail.flags {
+                continue;
+            }
+            let id = &mail.maildir_id;
+            let dst = maildir
+                .path()
+                .join("cur")
+                .join(file_name(id, &flags, None, cfg));
+            info!(
+                "changing flags of `{id}` from `{}` to `{flags}`",
+                mail.flags
+            );
+            fs::rename(&mail.path, dst).map_err(Error::Fs)?;
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
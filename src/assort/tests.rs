@@ -0,0 +1,603 @@
+//! Tests running the assortment on a temporary store.
+
+use std::{fs, path::PathBuf};
+
+use maildir::Maildir;
+use tempdir::TempDir;
+
+use crate::{
+    assort::{Options, bench, dedup_store, preview, reassort, run, verify},
+    config::{self, Config},
+};
+
+/// A temporary store with a maildir of new mails next to it.
+struct Store {
+    dir: TempDir,
+    cfg: Config,
+}
+
+impl Store {
+    /// Creates an empty store using the config with the options and folders in `config`.
+    fn new(config: &str) -> Self {
+        let dir = TempDir::new("lkml-test").unwrap();
+        let path = dir.path().join("config.toml");
+        let store = dir.path().join("store");
+        let config = format!("path = {store:?}\nquery = \"\"\naddresses = []\n{config}");
+        fs::write(&path, config).unwrap();
+        let cfg = config::load_from(&path).unwrap();
+        Self { dir, cfg }
+    }
+
+    /// Returns the maildir of `folder`, the root maildir for `INBOX`.
+    fn maildir(&self, folder: &str) -> PathBuf {
+        match folder {
+            "INBOX" => self.cfg.path.clone(),
+            folder => self.cfg.path.join(format!(".{folder}")),
+        }
+    }
+
+    /// Stores `mail` in `folder` with the maildir `id` and `flags`.
+    fn store(&self, folder: &str, id: &str, flags: &str, mail: &str) {
+        let cur = self.maildir(folder).join("cur");
+        fs::create_dir_all(&cur).unwrap();
+        fs::write(cur.join(format!("{id}:2,{flags}")), mail).unwrap();
+    }
+
+    /// Adds `mail` with the maildir `id` to the new mails.
+    fn add(&self, id: &str, mail: &str) {
+        self.add_bytes(id, "", mail.as_bytes());
+    }
+
+    /// Adds the raw `mail`, which doesn't need to be valid UTF-8, with the maildir `flags` to the
+    /// new mails.
+    fn add_bytes(&self, id: &str, flags: &str, mail: &[u8]) {
+        let cur = self.dir.path().join("new").join("cur");
+        fs::create_dir_all(&cur).unwrap();
+        fs::write(cur.join(format!("{id}:2,{flags}")), mail).unwrap();
+    }
+
+    /// Assorts the new mails into the store.
+    fn assort(&self, opts: Options<'_>) -> Result<super::Summary, super::Error> {
+        let new = self.dir.path().join("new");
+        for sub in ["cur", "new", "tmp"] {
+            fs::create_dir_all(new.join(sub)).unwrap();
+        }
+        run(
+            Maildir::from(new),
+            Maildir::from(self.cfg.path.clone()),
+            &self.cfg,
+            opts,
+        )
+    }
+
+    /// Assorts the stored mails again.
+    fn reassort(&self, confirm: bool) -> super::Summary {
+        let main = Maildir::from(self.cfg.path.clone());
+        reassort(main, &self.cfg, Options::default(), confirm).unwrap()
+    }
+
+    /// Returns the number of problems `verify` finds in the store.
+    fn verify(&self) -> usize {
+        verify(Maildir::from(self.cfg.path.clone()), &self.cfg).unwrap()
+    }
+
+    /// Returns the folders and flags of all stored files with the maildir `id`, sorted by folder.
+    fn find(&self, id: &str) -> Vec<(String, String)> {
+        let mut found = Vec::new();
+        let folders = fs::read_dir(&self.cfg.path)
+            .unwrap()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().unwrap();
+                name.strip_prefix('.').map(str::to_owned)
+            });
+        for folder in folders.chain(["INBOX".to_owned()]) {
+            let Ok(entries) = fs::read_dir(self.maildir(&folder).join("cur")) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().into_string().unwrap();
+                if let Some((_, flags)) = name
+                    .split_once(":2,")
+                    .filter(|(name, _)| name.split(',').next() == Some(id))
+                {
+                    found.push((folder.clone(), flags.to_owned()));
+                }
+            }
+        }
+        found.sort();
+        found
+    }
+
+    /// Returns the folders of all stored files with the maildir `id`.
+    fn folders(&self, id: &str) -> Vec<String> {
+        self.find(id)
+            .into_iter()
+            .map(|(folder, _)| folder)
+            .collect()
+    }
+}
+
+/// Returns a mail with `headers` (one `Name: value` per line) and `body`.
+fn mail(headers: &str, body: &str) -> String {
+    format!("From: Someone <someone@example.com>\nSubject: test\n{headers}\n\n{body}\n")
+}
+
+/// Folder config with `rust` and `net` folders, which also takes copies with `copy-net`.
+fn folders(copy_net: bool) -> String {
+    format!(
+        "[[folders]]\n\
+         name = \"rust\"\n\
+         keywords = [\"rust/\"]\n\
+         priority = 10\n\
+         [[folders]]\n\
+         name = \"net\"\n\
+         keywords = [\"net/\"]\n\
+         priority = 5\n\
+         copy = {copy_net}\n"
+    )
+}
+
+#[test]
+fn reply_to_copied_parent() {
+    let store = Store::new(&folders(true));
+    let parent = mail("Message-ID: <p@x>", "rust/ net/");
+    store.store("rust", "p1", "S", &parent);
+    store.store("net", "p2", "S", &parent);
+    store.add(
+        "r",
+        &mail("Message-ID: <r@x>\nIn-Reply-To: <p@x>", "thanks"),
+    );
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("r"), ["rust"]);
+    assert_eq!(store.verify(), 0);
+}
+
+#[test]
+fn cross_filed_reply() {
+    let store = Store::new(&format!("cross-file = true\n{}", folders(false)));
+    store.store("rust", "p", "S", &mail("Message-ID: <p@x>", "rust/"));
+    store.add("r", &mail("Message-ID: <r@x>\nIn-Reply-To: <p@x>", "net/"));
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("r"), ["net", "rust"]);
+    assert_eq!(store.verify(), 0);
+}
+
+#[test]
+fn thread_follows_matching_root() {
+    let store = Store::new(&folders(false));
+    store.add("a", &mail("Message-ID: <a@x>", "rust/"));
+    store.add("b", &mail("Message-ID: <b@x>\nIn-Reply-To: <a@x>", "b"));
+    store.add(
+        "c",
+        &mail(
+            "Message-ID: <c@x>\nIn-Reply-To: <b@x>\nReferences: <a@x> <b@x>",
+            "c",
+        ),
+    );
+    store.add(
+        "d",
+        &mail(
+            "Message-ID: <d@x>\nIn-Reply-To: <c@x>\nReferences: <a@x> <b@x> <c@x>",
+            "d",
+        ),
+    );
+    store.assort(Options::default()).unwrap();
+    for id in ["a", "b", "c", "d"] {
+        assert_eq!(store.folders(id), ["rust"], "{id}");
+    }
+}
+
+#[test]
+fn thread_pinned_to_multiple_folders() {
+    let store = Store::new(&folders(false));
+    store.store("rust", "p1", "S", &mail("Message-ID: <p1@x>", "rust/"));
+    store.store("net", "p2", "S", &mail("Message-ID: <p2@x>", "net/"));
+    store.add("a", &mail("Message-ID: <a@x>\nIn-Reply-To: <p1@x>", "a"));
+    store.add(
+        "b",
+        &mail(
+            "Message-ID: <b@x>\nIn-Reply-To: <p2@x>\nReferences: <a@x> <p2@x>",
+            "b",
+        ),
+    );
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("a"), ["rust"]);
+    assert_eq!(store.folders("b"), ["net"]);
+}
+
+#[test]
+fn reassort_follows_unconfigured_folders() {
+    let store = Store::new(&format!("auto-list-folders = true\n{}", folders(false)));
+    let parent = mail("Message-ID: <p@x>\nList-Id: <foo.example.com>", "p");
+    store.store("lists.foo_example_com", "p", "S", &parent);
+    store.store(
+        "INBOX",
+        "r",
+        "",
+        &mail("Message-ID: <r@x>\nIn-Reply-To: <p@x>", "r"),
+    );
+    let digest = mail("Message-ID: <lkml-digest.1.rust@localhost>", "rust/");
+    store.store("rust", "d", "", &digest);
+    store.reassort(false);
+    assert_eq!(store.folders("r"), ["INBOX"]);
+    store.reassort(true);
+    assert_eq!(store.folders("p"), ["lists.foo_example_com"]);
+    assert_eq!(store.folders("r"), ["lists.foo_example_com"]);
+    assert_eq!(store.folders("d"), ["rust"]);
+}
+
+#[test]
+fn notmuch_reply_to_stored_mail() {
+    let store = Store::new(&format!("{}[notmuch]\n", folders(false)));
+    store.store("INBOX", "p", "S", &mail("Message-ID: <p@x>", "rust/"));
+    store.add("r", &mail("Message-ID: <r@x>\nIn-Reply-To: <p@x>", "net/"));
+    let opts = Options {
+        dry_run: true,
+        ..Options::default()
+    };
+    let summary = store.assort(opts).unwrap();
+    assert_eq!(summary.delivered.keys().collect::<Vec<_>>(), ["net"]);
+}
+
+/// Config preferring the list `<a>` over `<b>`.
+const PREFER_LIST: &str = "folders = []\n\
+    [quirks]\n\
+    deduplicate = []\n\
+    prefer = []\n\
+    prefer-list = [\"<a>\", \"<b>\"]\n";
+
+#[test]
+fn prefer_list_keeps_preferred_copy() {
+    let store = Store::new(PREFER_LIST);
+    store.add("from-b", &mail("Message-ID: <m@x>\nList-Id: <b>", "m"));
+    store.add("from-a", &mail("Message-ID: <m@x>\nList-Id: <a>", "m"));
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("from-a"), ["INBOX"]);
+    assert!(store.folders("from-b").is_empty());
+}
+
+#[test]
+fn prefer_list_different_mail() {
+    let store = Store::new(PREFER_LIST);
+    store.store(
+        "INBOX",
+        "from-a",
+        "",
+        &mail("Message-ID: <m@x>\nList-Id: <a>", "m"),
+    );
+    store.add(
+        "from-b",
+        &mail("Message-ID: <m@x>\nList-Id: <b>", "something else"),
+    );
+    assert!(store.assort(Options::default()).is_err());
+}
+
+#[test]
+fn refiled_mail_loses_folder_flags() {
+    let store = Store::new(&folders(false).replace("copy = false", "set-flags = \"S\""));
+    store.add("a", &mail("Message-ID: <a@x>", "net/"));
+    store.add("b", &mail("Message-ID: <b@x>\nIn-Reply-To: <a@x>", "rust/"));
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.find("a"), [("rust".to_owned(), String::new())]);
+}
+
+#[test]
+fn skip_errors_quarantines() {
+    let store = Store::new(&format!(
+        "quarantine-folder = \"errors\"\n{}",
+        folders(false)
+    ));
+    store.store("INBOX", "m1", "", &mail("Message-ID: <m@x>", "one"));
+    store.add("m2", &mail("Message-ID: <m@x>", "two"));
+    let opts = Options {
+        skip_errors: true,
+        ..Options::default()
+    };
+    let summary = store.assort(opts).unwrap();
+    assert_eq!(summary.quarantined, 1);
+    assert!(summary.dropped.is_empty());
+    assert_eq!(store.folders("m2"), ["errors"]);
+    // the quarantined copy is not mistaken for a duplicate in another folder.
+    store.add("n", &mail("Message-ID: <n@x>", "rust/"));
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("n"), ["rust"]);
+    assert_eq!(store.verify(), 0);
+}
+
+#[test]
+fn copies_are_not_new() {
+    let store = Store::new(&folders(false));
+    let copy = mail("Message-ID: <m@x>", "rust/");
+    store.store("rust", "m1", "", &copy);
+    store.add("m2", &copy);
+    store.add("n", &mail("Message-ID: <n@x>", "n"));
+    let summary = store.assort(Options::default()).unwrap();
+    assert_eq!(summary.new, 1);
+    assert!(
+        summary
+            .commit_message(false)
+            .starts_with("update: 1 new mail(s)")
+    );
+}
+
+#[test]
+fn dry_run_leaves_store_alone() {
+    let store = Store::new(&folders(false));
+    store.add("m", &mail("Message-ID: <m@x>", "rust/"));
+    let opts = Options {
+        dry_run: true,
+        ..Options::default()
+    };
+    let summary = store.assort(opts).unwrap();
+    assert_eq!(summary.delivered.keys().collect::<Vec<_>>(), ["rust"]);
+    assert!(!store.cfg.path.exists());
+}
+
+#[test]
+fn max_deletions_counts_copies() {
+    let store = Store::new(&format!("max-deletions = 1\n{}", folders(false)));
+    for id in ["a", "b"] {
+        let copy = mail(&format!("Message-ID: <{id}@x>"), "rust/");
+        store.store("rust", &format!("{id}1"), "", &copy);
+        store.add(&format!("{id}2"), &copy);
+    }
+    assert!(store.assort(Options::default()).is_err());
+    let opts = Options {
+        allow_deletions: true,
+        ..Options::default()
+    };
+    assert_eq!(
+        store.assort(opts).unwrap().dropped.values().sum::<usize>(),
+        2
+    );
+}
+
+#[test]
+fn list_folders_only_for_delivered_mails() {
+    let store = Store::new(&format!("auto-list-folders = true\n{}", folders(false)));
+    store.add(
+        "a",
+        &mail("Message-ID: <a@x>\nList-Id: <a.example.com>", "rust/"),
+    );
+    store.add(
+        "b",
+        &mail("Message-ID: <b@x>\nList-Id: <b.example.com>", "b"),
+    );
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("a"), ["rust"]);
+    assert_eq!(store.folders("b"), ["lists.b_example_com"]);
+    assert!(!store.maildir("lists.a_example_com").exists());
+}
+
+#[test]
+fn resurfaced_parent_is_unread() {
+    let store = Store::new(&format!(
+        "resurface-threads = \"unread\"\n{}",
+        folders(false)
+    ));
+    store.store("rust", "p", "RS", &mail("Message-ID: <p@x>", "rust/"));
+    store.add("r", &mail("Message-ID: <r@x>\nIn-Reply-To: <p@x>", "r"));
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.find("p"), [("rust".to_owned(), "R".to_owned())]);
+    assert_eq!(store.folders("r"), ["rust"]);
+}
+
+#[test]
+fn bench_leaves_store_alone() {
+    let store = Store::new(&format!("auto-list-folders = true\n{}", folders(false)));
+    store.add(
+        "m",
+        &mail("Message-ID: <m@x>\nList-Id: <m.example.com>", "m"),
+    );
+    let corpus = store.dir.path().join("new");
+    let main = Maildir::from(store.cfg.path.clone());
+    bench(Maildir::from(corpus), main, &store.cfg, Options::default()).unwrap();
+    assert!(!store.cfg.path.exists());
+}
+
+#[test]
+fn preview_leaves_store_alone() {
+    let store = Store::new(&folders(false));
+    store.add("a", &mail("Message-ID: <a@x>", "net/"));
+    store.add("b", &mail("Message-ID: <b@x>\nIn-Reply-To: <a@x>", "b"));
+    let new = store.dir.path().join("new");
+    let main = Maildir::from(store.cfg.path.clone());
+    preview(Maildir::from(new), main, &store.cfg, Options::default()).unwrap();
+    assert!(!store.cfg.path.exists());
+}
+
+/// Returns a reply with `subject` without `In-Reply-To` to the mails in `references`.
+fn orphan(id: &str, subject: &str, references: &str) -> String {
+    format!(
+        "From: Someone <someone@example.com>\nSubject: {subject}\nMessage-ID: <{id}@x>\n\
+         References: {references}\n\nbody\n"
+    )
+}
+
+#[test]
+fn subject_threading() {
+    let store = Store::new(&format!("subject-threading = true\n{}", folders(false)));
+    store.store(
+        "rust",
+        "p",
+        "S",
+        &orphan("p", "[PATCH v2 1/3] rust: foo", "<cover@x>"),
+    );
+    store.add(
+        "match",
+        &orphan("match", "Re: [PATCH v2 1/3] rust: foo", "<cover@x>"),
+    );
+    store.add(
+        "other-subject",
+        &orphan("other-subject", "Re: rust: bar", "<cover@x>"),
+    );
+    store.add(
+        "other-refs",
+        &orphan("other-refs", "Re: rust: foo", "<other@x>"),
+    );
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("match"), ["rust"]);
+    assert_eq!(store.folders("other-subject"), ["INBOX"]);
+    assert_eq!(store.folders("other-refs"), ["INBOX"]);
+}
+
+/// Returns a mail with `headers` and the latin-1 body `un café`.
+fn latin1_mail(headers: &str) -> Vec<u8> {
+    let mut mail = mail(headers, "").into_bytes();
+    mail.pop();
+    mail.extend(b"un caf\xe9\n");
+    mail
+}
+
+#[test]
+fn latin1_body() {
+    let store = Store::new(&format!(
+        "default-charset = \"latin1\"\n{}",
+        folders(false).replace("\"net/\"", "\"café\"")
+    ));
+    store.add_bytes("undeclared", "", &latin1_mail("Message-ID: <u@x>"));
+    let declared = "Message-ID: <d@x>\nContent-Type: text/plain; charset=iso-8859-1";
+    store.add_bytes("declared", "", &latin1_mail(declared));
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("undeclared"), ["net"]);
+    assert_eq!(store.folders("declared"), ["net"]);
+}
+
+#[test]
+fn recipient_limits() {
+    let store = Store::new(
+        "[[folders]]\n\
+         name = \"fanout\"\n\
+         priority = 1\n\
+         min-recipients = 3\n\
+         max-recipients = 4\n",
+    );
+    for count in 2..=5 {
+        let to = (0..count)
+            .map(|i| format!("r{i}@example.com"))
+            .collect::<Vec<_>>();
+        let (to, cc) = to.split_at(1);
+        let headers = format!(
+            "Message-ID: <{count}@x>\nTo: {}\nCc: {}",
+            to.join(", "),
+            cc.join(", ")
+        );
+        store.add(&count.to_string(), &mail(&headers, "body"));
+    }
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("2"), ["INBOX"]);
+    assert_eq!(store.folders("3"), ["fanout"]);
+    assert_eq!(store.folders("4"), ["fanout"]);
+    assert_eq!(store.folders("5"), ["INBOX"]);
+}
+
+#[test]
+fn from_rules() {
+    let store = Store::new(
+        "[[folders]]\n\
+         name = \"maintainers\"\n\
+         from-keywords = [\"@kernel\\\\.org>\"]\n\
+         priority = 10\n\
+         [[folders]]\n\
+         name = \"corp\"\n\
+         from-name = [\"\\\\(Example Corp\\\\)$\"]\n\
+         priority = 5\n",
+    );
+    store.add(
+        "multiple",
+        &mail(
+            "Message-ID: <m@x>\nFrom: Maintainer <maint@kernel.org>",
+            "m",
+        ),
+    );
+    store.add(
+        "name",
+        "From: \"Jane Doe (Example Corp)\" <jane@example.com>\nSubject: test\n\
+         Message-ID: <n@x>\n\nn\n",
+    );
+    store.add(
+        "original",
+        "From: Jane Doe (Example Corp) via list <list@kernel.org>\n\
+         X-Original-From: \"Jane Doe (Example Corp)\" <jane@example.com>\n\
+         Subject: test\nMessage-ID: <o@x>\n\no\n",
+    );
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("multiple"), ["maintainers"]);
+    assert_eq!(store.folders("name"), ["corp"]);
+    assert_eq!(store.folders("original"), ["corp"]);
+}
+
+#[test]
+fn exclude_keywords_demote() {
+    let store = Store::new(&folders(false).replace(
+        "keywords = [\"rust/\"]\n",
+        "keywords = [\"rust/\"]\nexclude-keywords = [\"Documentation/\"]\n",
+    ));
+    store.add("code", &mail("Message-ID: <c@x>", "rust/ net/"));
+    store.add(
+        "docs",
+        &mail("Message-ID: <d@x>", "rust/ net/ Documentation/"),
+    );
+    store.add(
+        "docs-only",
+        &mail("Message-ID: <o@x>", "rust/ Documentation/"),
+    );
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("code"), ["rust"]);
+    assert_eq!(store.folders("docs"), ["net"]);
+    assert_eq!(store.folders("docs-only"), ["INBOX"]);
+}
+
+#[test]
+fn references_without_in_reply_to() {
+    let store = Store::new(&folders(false));
+    store.store("net", "root", "S", &mail("Message-ID: <root@x>", "net/"));
+    store.store("rust", "p", "S", &mail("Message-ID: <p@x>", "rust/"));
+    store.add(
+        "r",
+        &mail("Message-ID: <r@x>\nReferences: <root@x> <p@x>", "r"),
+    );
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.folders("r"), ["rust"]);
+}
+
+#[test]
+fn equal_priority_keeps_config_order() {
+    let folder = |name: &str| {
+        format!("[[folders]]\nname = \"{name}\"\nkeywords = [\"shared/\"]\npriority = 1\n")
+    };
+    for order in [["first", "second"], ["second", "first"]] {
+        let store = Store::new(&order.map(folder).concat());
+        store.add("m", &mail("Message-ID: <m@x>", "shared/"));
+        store.assort(Options::default()).unwrap();
+        assert_eq!(store.folders("m"), [order[0]]);
+    }
+}
+
+#[test]
+fn passed_flag_is_kept() {
+    let store = Store::new(&folders(false));
+    store.store("INBOX", "moved", "PS", &mail("Message-ID: <m@x>", "rust/"));
+    store.reassort(true);
+    assert_eq!(store.find("moved"), [("rust".to_owned(), "PS".to_owned())]);
+    let new = mail("Message-ID: <n@x>", "net/");
+    store.add_bytes("new", "P", new.as_bytes());
+    store.assort(Options::default()).unwrap();
+    assert_eq!(store.find("new"), [("net".to_owned(), "P".to_owned())]);
+}
+
+#[test]
+fn dedup_store_keeps_different_mails() {
+    let store = Store::new(&folders(false));
+    let copy = mail("Message-ID: <c@x>", "rust/");
+    store.store("rust", "c1", "", &copy);
+    store.store("rust", "c2", "", &copy);
+    store.store("rust", "d1", "", &mail("Message-ID: <d@x>", "one"));
+    store.store("rust", "d2", "", &mail("Message-ID: <d@x>", "two"));
+    let main = Maildir::from(store.cfg.path.clone());
+    assert_eq!(dedup_store(main, &store.cfg, true).unwrap(), 1);
+    assert_eq!(store.folders("c1").len() + store.folders("c2").len(), 1);
+    assert_eq!(store.folders("d1"), ["rust"]);
+    assert_eq!(store.folders("d2"), ["rust"]);
+}
@@ -0,0 +1,103 @@
+use std::{collections::HashMap, fs, io, rc::Rc};
+
+use maildir::Maildir;
+
+use crate::{
+    assort::{
+        Error, Options, existing_mails, folders,
+        mail::{Mail, Type},
+        oversized_threads, parse_all,
+    },
+    config::Config,
+};
+
+/// Checks the store for inconsistencies without modifying anything.
+///
+/// Prints every found problem and returns how many there were. The following is checked:
+/// - every `Message-ID` is only stored once (or once per folder with [`Config::files_copies`]),
+/// - replies are stored in the same folder as their parent (if it is in the store), with copies
+///   in at least one of them,
+/// - there are no leftover files in the `tmp` directories.
+pub fn verify(main: Maildir, cfg: &Config) -> Result<usize, Error> {
+    let (folders, _) = folders(main, cfg);
+    let mut problems = 0;
+    let mut mails = existing_mails(&folders, cfg)?;
+    let mut indexed: HashMap<String, Vec<Rc<Mail<'_>>>> = HashMap::with_capacity(mails.len());
+    let paths = mails
+        .iter()
+        .map(|(mail, _)| mail.path().to_owned())
+        .collect::<Vec<_>>();
+    let threads = Options::default().parse_threads(cfg);
+    parse_all(&mut mails, cfg, threads, |i, mail| {
+        match mail {
+            Ok(mail) => indexed
+                .entry(mail.id.clone())
+                .or_default()
+                .push(Rc::new(mail)),
+            Err(e) => {
+                println!("`{}` cannot be parsed: {e}", paths[i].display());
+                problems += 1;
+            }
+        }
+        Ok(())
+    })?;
+    let name = |typ: Type| match typ {
+        Type::Folder(i) => folders[i].name.as_str(),
+        Type::New => unreachable!(),
+    };
+    for (id, mails) in &indexed {
+        let duplicate = if cfg.files_copies() {
+            mails
+                .iter()
+                .enumerate()
+                .any(|(i, m)| mails[..i].iter().any(|o| o.typ == m.typ))
+        } else {
+            mails.len() > 1
+        };
+        if duplicate {
+            println!("`{id}` is stored {} times:", mails.len());
+            for mail in mails {
+                println!("\t{} ({})", mail.path.display(), name(mail.typ));
+            }
+            problems += 1;
+        }
+    }
+    let oversized = oversized_threads(&indexed, cfg);
+    for mails in indexed.values() {
+        let mail = &mails[0];
+        if oversized.contains(&mail.id) {
+            continue;
+        }
+        let Some(parents) = mail.parent.as_ref().and_then(|p| indexed.get(p)) else {
+            continue;
+        };
+        // with copies, a thread only has to share one of the folders of the mails.
+        if parents
+            .iter()
+            .all(|p| mails.iter().all(|mail| p.typ != mail.typ))
+        {
+            println!(
+                "`{}` ({}) is not in the folder of its parent `{}` ({})",
+                mail.path.display(),
+                name(mail.typ),
+                parents[0].path.display(),
+                name(parents[0].typ)
+            );
+            problems += 1;
+        }
+    }
+    for folder in &folders {
+        let tmp = folder.maildir.path().join("tmp");
+        let entries = match fs::read_dir(&tmp) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(Error::MailIO(e)),
+        };
+        for entry in entries {
+            let entry = entry.map_err(Error::MailIO)?;
+            println!("leftover temporary file `{}`", entry.path().display());
+            problems += 1;
+        }
+    }
+    Ok(problems)
+}
@@ -1,28 +1,96 @@
 // `Regex` contains interior mutability, but we don't depend on that for hashing/equality
 #![expect(clippy::mutable_key_type)]
 
-use std::{collections::HashSet, fs, hash::Hash, io, path::PathBuf};
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    hash::Hash,
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
+use chrono::{Local, NaiveTime};
 use directories_next::BaseDirs;
-use regex::Regex;
+use encoding_rs::Encoding;
+use regex::{Regex, RegexBuilder};
 use serde::Deserialize;
 use thiserror::Error;
+use tracing::warn;
+
+use crate::{assort::MAILDIR_FLAGS, hash::fnv1a, lei};
 
 /// Configuration for `lkml`.
+///
+/// # Remote config
+///
+/// `remote-url` is the URL of a shared config file providing `query` and `folders`. The file is
+/// downloaded with `curl` (which has to be installed) on every run and cached, the cached copy is
+/// used when the download fails. A `query` in the local config overrides the remote one and local
+/// folders override remote folders with the same name.
+///
+/// ```toml
+/// remote-url = "https://example.org/team/lkml.toml"
+/// ```
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     /// Path to the main maildir directory.
+    ///
+    /// This has to be the root maildir and not a Maildir++ subfolder, the folders are created as
+    /// `.$name` next to its `cur`, `new` and `tmp` directories.
     pub path: PathBuf,
 
-    /// `lei q` query to run.
+    /// URL of the public-inbox to query, e.g. a mirror or the inbox of a single list.
+    ///
+    /// It is also used for the links to the mails. Defaults to `https://lore.kernel.org/all/`.
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// inbox = "https://lore.kernel.org/rust-for-linux/"
+    /// ```
+    #[serde(default = "default_inbox")]
+    pub inbox: String,
+
+    /// `lei q` query to run against [`inbox`](Self::inbox).
+    ///
+    /// Either this, [`queries`](Self::queries) or [`saved-search`](Self::saved_search) has to be
+    /// given.
     ///
     /// # Examples
     ///
     /// ```toml
     /// query = "dfn:^rust/ OR l:rust-for-linux.vger.kernel.org"
     /// ```
-    pub query: String,
+    pub query: Option<String>,
+
+    /// Additional queries, each against its own public-inbox.
+    ///
+    /// All queries search the same interval and their mails are assorted together.
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// [[queries]]
+    /// inbox = "https://lore.kernel.org/netdev/"
+    /// query = "dfn:^drivers/net/ethernet/intel/"
+    /// ```
+    #[serde(default)]
+    pub queries: Vec<Query>,
+
+    /// Output maildir of a `lei` saved search to use instead of `query`.
+    ///
+    /// `lei up` is run on it and the mails it produced are moved out of it and assorted. The
+    /// interval given on the command line is ignored, since the saved search has its own.
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// saved-search = "/home/me/mail/lei/rust"
+    /// ```
+    #[serde(rename = "saved-search")]
+    pub saved_search: Option<PathBuf>,
 
     /// Quirk fixes for mail clients, mailing lists etc.
     #[serde(default)]
@@ -33,6 +101,30 @@ pub struct Config {
     /// All mails from these addresses will be marked as read, since you sent them yourself.
     pub addresses: HashSet<String>,
 
+    /// Set of `List-Id`'s whose mails are marked as read, regardless of the folder they go into.
+    ///
+    /// Like [`mark-read`](Folder::mark_read), the mails are still flagged if they match
+    /// [`flagging`](Self::flagging) keywords, which keeps them unread.
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// mark-read-lists = ["<linux-kernel.vger.kernel.org>"]
+    /// ```
+    #[serde(rename = "mark-read-lists", default)]
+    pub mark_read_lists: HashSet<String>,
+
+    /// Headers naming the author of a mail, in order of precedence.
+    ///
+    /// Some mailing lists rewrite the `From` header to their own address and put the original one
+    /// into another header. The first header in this list that is present in a mail is used to
+    /// detect mails from your own [`addresses`](Self::addresses), for `from-name` rules and for
+    /// the [`block`](Self::block) list.
+    ///
+    /// Defaults to `["X-Original-From", "From"]`.
+    #[serde(rename = "author-headers", default = "default_author_headers")]
+    pub author_headers: Vec<String>,
+
     /// Control which emails have the `Flagged` flag set.
     #[serde(default)]
     pub flagging: Flagging,
@@ -40,15 +132,364 @@ pub struct Config {
     /// Array of folders to categorize mails into.
     pub folders: Vec<Folder>,
 
+    /// Match all keywords (including `trailers`, `from-name` and `flagging.keywords`) ignoring
+    /// case, as if every one of them started with `(?i)`.
+    #[serde(rename = "case-insensitive", default)]
+    pub case_insensitive: bool,
+
+    /// Put a copy of a mail into every folder it matches, not only into the one with the highest
+    /// priority.
+    ///
+    /// The copies share the same `Message-ID`. Replies follow the folder with the highest priority.
+    #[serde(rename = "cross-file", default)]
+    pub cross_file: bool,
+
+    /// Maximum number of mails in a thread that are kept in a single folder.
+    ///
+    /// Mails of threads with more mails than this are no longer put into the folder of their
+    /// parent, instead every mail is assorted by its own content. The size of a thread is the
+    /// number of mails sharing the same root mail.
+    #[serde(rename = "max-thread-size")]
+    pub max_thread_size: Option<usize>,
+
+    /// Put new mails whose parent is not stored into the folder of a stored mail with the same
+    /// subject.
+    ///
+    /// Subjects are compared after removing all `Re:` prefixes and leading tags like
+    /// `[PATCH v2 1/3]`. To avoid false positives, the new mail also needs to have the stored mail
+    /// (or one of the mails referenced by it) in its `References` header.
+    #[serde(rename = "subject-threading", default)]
+    pub subject_threading: bool,
+
+    /// Put new mails into the folder of their already stored replies.
+    ///
+    /// Replies are sometimes delivered before the mail they reply to. Within a single run this
+    /// doesn't matter, since all new mails are indexed before any of them is assorted. But if the
+    /// parent only arrives in a later run, the reply was already assorted by its own content. When
+    /// enabled, the late parent is then put into the folder of its stored reply instead of being
+    /// assorted by its content. Stored replies are never moved. Has no effect with `--only-new`,
+    /// since the stored mails are not read then.
+    #[serde(rename = "follow-replies", default)]
+    pub follow_replies: bool,
+
+    /// What to do when a new reply to an already stored mail arrives.
+    ///
+    /// - `"unread"`: the stored parent mail is marked as unread again,
+    /// - `"flag"`: the new reply is flagged.
+    ///
+    /// Nothing is done for replies sent by one of your [`addresses`](Self::addresses). If not
+    /// specified, the reply is only put into the folder of its parent.
+    #[serde(rename = "resurface-threads")]
+    pub resurface_threads: Option<Resurface>,
+
+    /// Put mails that did not match any folder into a folder per mailing list instead of `INBOX`.
+    ///
+    /// The folder is named `lists.$id` after the `List-Id` of the mail, with all characters except
+    /// letters, digits, `-` and `_` replaced by `_`. It is created if it does not exist. Mails
+    /// without a `List-Id` still go into `INBOX`.
+    #[serde(rename = "auto-list-folders", default)]
+    pub auto_list_folders: bool,
+
+    /// What to do with mails that do not belong to a thread and did not match any folder.
+    ///
+    /// - `"inbox"`: the mail is put into `INBOX`,
+    /// - `"drop"`: the mail is deleted.
+    ///
+    /// Mails going into a folder because of [`auto-list-folders`](Self::auto_list_folders) or
+    /// [`empty-body`](Self::empty_body) are not affected. Defaults to `"inbox"`.
+    #[serde(default)]
+    pub unmatched: Unmatched,
+
+    /// What to do with mails without any text in their body, e.g. delivery notices.
+    ///
+    /// - `"drop"`: the mail is deleted,
+    /// - `{ folder = "name" }`: the mail is moved to the folder `name`, it is created if it is
+    ///   not configured.
+    ///
+    /// This only applies to mails that do not belong to a thread and did not match any folder. If
+    /// not specified, they are put into `INBOX`.
+    #[serde(rename = "empty-body")]
+    pub empty_body: Option<EmptyBody>,
+
+    /// Folder for mails without a `Message-ID` header, it is created if it is not configured.
+    ///
+    /// These mails get an id derived from their content and are not threaded. If not specified,
+    /// such mails abort the run. With `--skip-errors`, mails that are inconsistent with the store
+    /// are moved here as well (into `lkml-errors` if not specified). The mails in this folder are
+    /// ignored when checking new mails against the store, by `verify` and by `dedup-store`.
+    #[serde(rename = "quarantine-folder")]
+    pub quarantine_folder: Option<String>,
+
+    /// Add a `X-Lkml-Folder` header to assorted mails naming their folder and the rule that put
+    /// them there, e.g. `X-Lkml-Folder: rust (keyword: diff --git a/rust/)`.
+    ///
+    /// Note that this modifies the content of the mails.
+    #[serde(rename = "tag-header", default)]
+    pub tag_header: bool,
+
+    /// Add a `X-Lkml-Archive-URL` header to assorted mails linking to them on lore, e.g.
+    /// `X-Lkml-Archive-URL: https://lore.kernel.org/all/20250101.1234-1-me@example.org/`.
+    ///
+    /// Note that this modifies the content of the mails.
+    #[serde(rename = "archive-header", default)]
+    pub archive_header: bool,
+
+    /// Honor the `Status` and `X-Status` headers of mbox clients.
+    ///
+    /// When enabled, mails with `R` in their `Status` header are marked as read, `F` and `A` in
+    /// their `X-Status` header mark them as flagged and replied respectively. These flags are set
+    /// in addition to the ones computed by `lkml`.
+    #[serde(rename = "status-headers", default)]
+    pub status_headers: bool,
+
+    /// Also scan the text of attachments for keywords.
+    ///
+    /// Some patches are sent as `.patch`/`.diff` attachments instead of inline. When enabled, the
+    /// decoded text of attachments with a textual content type or a `.patch`/`.diff` file name is
+    /// appended to the body before matching. Disabled by default, since decoding costs time.
+    #[serde(rename = "scan-attachments", default)]
+    pub scan_attachments: bool,
+
+    /// Match keywords against the `text/plain` parts of multipart mails.
+    ///
+    /// Without this, only the top-level body is used, which for `multipart/mixed` or
+    /// `multipart/alternative` mails does not contain the actual text. When enabled, all inline
+    /// `text/plain` parts are decoded and concatenated instead.
+    #[serde(rename = "scan-multipart", default)]
+    pub scan_multipart: bool,
+
+    /// Charset used to decode mail bodies that don't declare one, e.g. `"latin1"` or `"koi8-r"`.
+    ///
+    /// Bodies declaring a charset in their `Content-Type` header are always decoded with it. By
+    /// default, undeclared bodies are treated as `us-ascii`, which mangles the non-ASCII text of
+    /// some older mails and prevents keywords containing it from matching.
+    #[serde(rename = "default-charset")]
+    pub default_charset: Option<String>,
+
+    /// Conventions of the mail client or server reading the maildirs.
+    ///
+    /// - `"standard"`: plain maildir file names,
+    /// - `"dovecot"`: the size of the mail is added to the file names of delivered mails
+    ///   (`,S=<size>`), which dovecot uses to avoid reading them.
+    ///
+    /// The flags `lkml` sets (`F`, `R` and `S`) are interpreted the same way by all clients.
+    /// Defaults to `"standard"`.
+    #[serde(rename = "maildir-dialect", default)]
+    pub maildir_dialect: MaildirDialect,
+
+    /// Number of threads used to parse the mails, defaults to the number of CPUs.
+    ///
+    /// All mails are kept in memory during a run, this only limits how many are read and parsed
+    /// at the same time. A mail takes roughly its file size plus about 1 KiB for its parsed
+    /// headers, on the order of 10 KiB for a typical mailing list mail. Lower this on machines
+    /// with little memory or slow disks. Can be overridden with `--parse-concurrency`.
+    #[serde(rename = "parse-concurrency")]
+    pub parse_concurrency: Option<usize>,
+
+    /// Maximum number of mails deleted in a single run.
+    ///
+    /// If more new mails would be deleted (e.g. because of an overly broad `ignore` config or
+    /// wrongly detected duplicates), the run is aborted before anything is changed. Use
+    /// `--allow-deletions` to delete them anyway. Dropped copies of already stored mails are
+    /// counted as well, so this needs to be above the number of mails a run usually downloads
+    /// again.
+    #[serde(rename = "max-deletions")]
+    pub max_deletions: Option<usize>,
+
+    /// Number of seconds after a completed run during which new runs are skipped.
+    ///
+    /// This avoids downloading the same mails again when `lkml` is accidentally started twice.
+    /// Use `--force` to run anyway.
+    pub cooldown: Option<u64>,
+
+    /// Number of times a failed `lei q` or `lei up` is retried.
+    ///
+    /// lore sometimes fails with temporary errors. The retries are delayed by 1s, 2s, 4s and so
+    /// on. Defaults to `3`.
+    #[serde(rename = "lei-retries", default = "default_lei_retries")]
+    pub lei_retries: u32,
+
+    /// Number of seconds after which a hanging `lei q` or `lei up` is killed.
+    ///
+    /// The attempt counts as failed and is retried according to
+    /// [`lei-retries`](Self::lei_retries). If not specified, `lei` may run forever.
+    #[serde(rename = "lei-timeout")]
+    pub lei_timeout: Option<u64>,
+
+    /// Directory in which the mails downloaded by `lei q` are cached.
+    ///
+    /// Runs within [`cache-ttl`](Self::cache_ttl) of the download with the same query and
+    /// interval use the cached mails instead of querying lore again. Useful while tuning the
+    /// folder rules. Not used with `saved-search`.
+    #[serde(rename = "cache-dir")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Number of seconds for which the cached mails in [`cache-dir`](Self::cache_dir) are used.
+    /// Defaults to `3600`.
+    #[serde(rename = "cache-ttl", default = "default_cache_ttl")]
+    pub cache_ttl: u64,
+
+    /// Number of seconds to wait for another instance of `lkml` working on the same store to
+    /// finish.
+    ///
+    /// Only one instance can download, assort and commit mails at the same time. Defaults to `0`,
+    /// so a second instance fails immediately.
+    #[serde(rename = "lock-wait", default)]
+    pub lock_wait: u64,
+
+    /// Number of seconds to wait for the user to acknowledge an error before terminating.
+    ///
+    /// When an internal error occurs, `lkml` waits for the user to press enter before deleting the
+    /// temporary directory, so the offending mails can be inspected. If not specified, it waits
+    /// forever. If the timeout passes, the offending mails are copied into a new directory in
+    /// `~/.cache/lkml/errors` before terminating.
+    #[serde(rename = "error-prompt-timeout")]
+    pub error_prompt_timeout: Option<u64>,
+
     /// Mail client configuration.
     ///
     /// If not specified, no mail client will be opened.
     pub client: Option<Client>,
 
+    /// Time of day during which the mail client is not launched.
+    ///
+    /// Mails are still downloaded, assorted and committed during quiet hours.
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// quiet-hours = { start = "22:00", end = "07:00" }
+    /// ```
+    #[serde(rename = "quiet-hours")]
+    pub quiet_hours: Option<QuietHours>,
+
+    /// Tag the mails in notmuch instead of moving them into folders.
+    ///
+    /// All mails are stored in the root maildir and get the names of the folders they would have
+    /// been moved to as tags. Read mails lose the `unread` tag and flagged ones get `flagged`.
+    /// `notmuch new` is run before tagging them. Since stored mails are not in the folders, replies
+    /// only get the tags of their parent if both are downloaded in the same run, replies to stored
+    /// mails are assorted by their own content.
+    pub notmuch: Option<Notmuch>,
+
     /// Git integration.
     pub git: Option<Git>,
 
     pub ignore: Option<Ignore>,
+
+    /// Senders to never receive mails from.
+    pub block: Option<Block>,
+}
+
+impl Config {
+    /// Returns the `(inbox, query)` pairs of [`Config::query`] and [`Config::queries`].
+    pub fn queries(&self) -> Vec<(&str, &str)> {
+        self.query
+            .iter()
+            .map(|query| (self.inbox.as_str(), query.as_str()))
+            .chain(
+                self.queries
+                    .iter()
+                    .map(|q| (q.inbox.as_deref().unwrap_or(&self.inbox), q.query.as_str())),
+            )
+            .collect()
+    }
+
+    /// Returns the name of the folder for mails that are inconsistent with the store, see
+    /// [`quarantine-folder`](Self::quarantine_folder).
+    pub fn quarantine(&self) -> &str {
+        self.quarantine_folder.as_deref().unwrap_or("lkml-errors")
+    }
+
+    /// Returns whether mails can be stored in multiple folders, because of
+    /// [`cross-file`](Self::cross_file) or a folder with [`copy`](Folder::copy).
+    pub fn files_copies(&self) -> bool {
+        self.cross_file || self.folders.iter().any(|f| f.copy)
+    }
+
+    /// Returns the problems of the folder configuration that can be found without any mails.
+    ///
+    /// These are duplicate folder names, folders without any rules (they never receive mails,
+    /// except for the `INBOX`, the `empty-body` and the `quarantine-folder`) and rules that are
+    /// shadowed by the same rule of a folder that is tried earlier.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (i, folder) in self.folders.iter().enumerate() {
+            if self.folders[..i].iter().any(|f| f.name == folder.name) {
+                problems.push(format!(
+                    "folder `{}` is configured multiple times",
+                    folder.name
+                ));
+            }
+        }
+        let empty_body = match &self.empty_body {
+            Some(EmptyBody::Folder(name)) => Some(name),
+            _ => None,
+        };
+        // the order in which the folders are tried, see `Folder::priority`.
+        let mut folders = self.folders.iter().collect::<Vec<_>>();
+        folders.sort_by_key(|f| std::cmp::Reverse(f.priority));
+        for (i, folder) in folders.iter().enumerate() {
+            let rules = folder.rules();
+            if rules.iter().all(|(_, kws)| kws.is_empty())
+                && folder.min_recipients.is_none()
+                && folder.max_recipients.is_none()
+                && folder.name != "INBOX"
+                && empty_body != Some(&folder.name)
+                && self.quarantine_folder.as_ref() != Some(&folder.name)
+            {
+                problems.push(format!(
+                    "folder `{}` has no rules and never matches",
+                    folder.name
+                ));
+            }
+            // folders with `copy` (or all with `cross-file`) get their mails even if an earlier
+            // folder matched.
+            if folder.copy || self.cross_file {
+                continue;
+            }
+            for earlier in folders[..i]
+                .iter()
+                .filter(|f| f.exclude_keywords.is_empty())
+            {
+                for ((rule, kws), (_, earlier_kws)) in rules.iter().zip(earlier.rules()) {
+                    for kw in kws.intersection(earlier_kws) {
+                        problems.push(format!(
+                            "`{rule}` `{kw}` of folder `{}` is shadowed by folder `{}`",
+                            folder.name, earlier.name
+                        ));
+                    }
+                }
+            }
+        }
+        problems
+    }
+}
+
+fn default_author_headers() -> Vec<String> {
+    vec!["X-Original-From".to_owned(), "From".to_owned()]
+}
+
+fn default_inbox() -> String {
+    lei::DEFAULT_INBOX.to_owned()
+}
+
+fn default_lei_retries() -> u32 {
+    3
+}
+
+fn default_cache_ttl() -> u64 {
+    3600
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Query {
+    /// URL of the public-inbox to query, defaults to the global [`inbox`](Config::inbox).
+    pub inbox: Option<String>,
+
+    /// `lei q` query to run.
+    pub query: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -70,9 +511,101 @@ pub struct Folder {
     #[serde(default)]
     pub keywords: HashSet<Keyword>,
 
+    /// Set of strings that prevent a mail from matching this folder if they are found in its
+    /// body.
+    ///
+    /// Such a mail can still match a folder with lower priority. Replies to mails stored in this
+    /// folder are put into it regardless.
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// keywords = ["rust/kernel"]
+    /// exclude-keywords = ["Documentation/"]
+    /// ```
+    #[serde(rename = "exclude-keywords", default)]
+    pub exclude_keywords: HashSet<Keyword>,
+
+    /// Set of patterns to match the `Subject` header against. If one matches, the email is moved
+    /// to this folder.
+    ///
+    /// These are checked before the `keywords` of the same folder.
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// subject-keywords = ["^\\[GIT PULL\\]"]
+    /// ```
+    #[serde(rename = "subject-keywords", default)]
+    pub subject_keywords: HashSet<Keyword>,
+
+    /// Set of patterns to scan the trailers of a patch for. If one matches, the email is moved to
+    /// this folder.
+    ///
+    /// Trailers are the `Key: value` lines at the end of the commit message, such as
+    /// `Signed-off-by:` or `Reviewed-by:`.
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// trailers = ["^(Reviewed|Acked|Tested)-by: .*<me@example.org>"]
+    /// ```
+    #[serde(default)]
+    pub trailers: HashSet<Keyword>,
+
+    /// Set of patterns to match the display names of the `From` header against. If one matches,
+    /// the email is moved to this folder.
+    ///
+    /// The display name is the part before the address, e.g. `Jane Doe` in
+    /// `Jane Doe <jane@example.org>`.
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// from-name = ["\\(Example Corp\\)$"]
+    /// ```
+    #[serde(rename = "from-name", default)]
+    pub from_name: HashSet<Keyword>,
+
+    /// Set of patterns to match the author headers (see [`author-headers`](Config::author_headers))
+    /// against. If one matches, the email is moved to this folder.
+    ///
+    /// Unlike `from-name`, this matches the full header values including the addresses. Multiple
+    /// headers or values are joined with `, `.
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// from-keywords = ["<maintainer@example\\.org>", "@kernel\\.org>"]
+    /// ```
+    #[serde(rename = "from-keywords", default)]
+    pub from_keywords: HashSet<Keyword>,
+
+    /// Minimum number of addresses in the `To` and `Cc` headers. If set, mails with at least this
+    /// many recipients are moved to this folder.
+    ///
+    /// Together with `max-recipients`, the number needs to lie between both (inclusive).
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// # patches sent to lots of maintainers
+    /// min-recipients = 20
+    /// ```
+    #[serde(rename = "min-recipients")]
+    pub min_recipients: Option<usize>,
+
+    /// Maximum number of addresses in the `To` and `Cc` headers. If set, mails with at most this
+    /// many recipients are moved to this folder.
+    #[serde(rename = "max-recipients")]
+    pub max_recipients: Option<usize>,
+
     /// Priority of this folder compared to other folders.
     ///
-    /// Higher priority folders will be preferred if their `match-body` value matches.
+    /// Higher priority folders will be preferred if their `subject-keywords`, `keywords`,
+    /// `trailers`, `from-name`, `from-keywords` or recipient limits match.
+    /// Folders with the same priority are tried in the order they are declared in, folders from
+    /// a [`remote-url`](Config#remote-config) after the local ones.
     pub priority: usize,
 
     /// Mark all emails delivered to this folder as read.
@@ -81,6 +614,47 @@ pub struct Folder {
     #[serde(rename = "mark-read", default)]
     pub mark_read: bool,
 
+    /// Put a copy of every mail matching this folder into it, even if it goes into a folder with a
+    /// higher priority.
+    ///
+    /// Like [`cross-file`](Config::cross_file), but only for this folder. Replies follow the
+    /// folder with the highest priority and get a copy here if they match this folder themselves.
+    #[serde(default)]
+    pub copy: bool,
+
+    /// Keep mails unread despite `mark-read` if they are sent directly to [`Ignore::name`].
+    ///
+    /// Useful to still notice replies that `CC` or are addressed to you in a muted folder.
+    #[serde(rename = "unread-on-mention", default)]
+    pub unread_on_mention: bool,
+
+    /// Maildir flags to set on all mails delivered to this folder, e.g. `"RS"` to mark automated
+    /// mails as replied and read.
+    ///
+    /// Any of `D` (draft), `F` (flagged), `P` (passed), `R` (replied), `S` (seen) and `T`
+    /// (trashed). Flagging keywords still clear `S`.
+    #[serde(rename = "set-flags", default)]
+    pub set_flags: String,
+
+    /// Summarize the new mails of this folder in a single digest mail delivered to it.
+    ///
+    /// - `"also"`: the digest is delivered in addition to the mails,
+    /// - `"only"`: the mails are marked as read (unless they are flagged), so only the digest
+    ///   shows up as unread.
+    ///
+    /// The digest lists the subject, author and lore link of every new mail.
+    pub digest: Option<Digest>,
+
+    /// Deliver the mails into a subfolder per month or year, e.g. `rust.2024-06` or `rust.2024`.
+    ///
+    /// - `"month"`: one subfolder per month,
+    /// - `"year"`: one subfolder per year.
+    ///
+    /// The subfolders are created when needed. All mails of a thread go into the subfolder for the
+    /// `Date` of the first mail of the thread, so threads are not split at the end of a month.
+    #[serde(rename = "archive-by")]
+    pub archive_by: Option<ArchiveBy>,
+
     /// Set of keywords used to mark mails with the `Flagged` flag.
     ///
     /// If this is set, it overrides the global [`flagging.keywords`](Flagging::keywords) configuration option.
@@ -88,6 +662,19 @@ pub struct Folder {
     pub flagging_keywords: Option<HashSet<Keyword>>,
 }
 
+impl Folder {
+    /// Returns the keyword based rules of this folder with their config names.
+    fn rules(&self) -> [(&'static str, &HashSet<Keyword>); 5] {
+        [
+            ("subject-keywords", &self.subject_keywords),
+            ("keywords", &self.keywords),
+            ("trailers", &self.trailers),
+            ("from-name", &self.from_name),
+            ("from-keywords", &self.from_keywords),
+        ]
+    }
+}
+
 #[derive(Deserialize, Debug, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Quirks {
@@ -105,6 +692,22 @@ pub struct Quirks {
     /// ```
     pub deduplicate: HashSet<String>,
 
+    /// `List-Id`'s in order of preference for mails received via multiple lists.
+    ///
+    /// When the same mail is downloaded from several of these lists in one run, only the copy from
+    /// the list that comes first is kept, the others are deleted. Copies from lists that are not
+    /// in here are deleted as well. A copy that is already stored is never replaced. This only
+    /// applies to copies with the same body, different mails with the same `Message-ID` are still
+    /// an error (see `--skip-errors`).
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// prefer-list = ["<rust-for-linux.vger.kernel.org>", "<linux-kernel.vger.kernel.org>"]
+    /// ```
+    #[serde(rename = "prefer-list", default)]
+    pub prefer_list: Vec<String>,
+
     /// Set of preferred `Message-ID`s.
     ///
     /// Some mail clients opt to send emails with multiple `Message-ID`s. In the case when the
@@ -113,6 +716,50 @@ pub struct Quirks {
     pub prefer: HashSet<String>,
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resurface {
+    Unread,
+    Flag,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaildirDialect {
+    #[default]
+    Standard,
+    Dovecot,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Digest {
+    Also,
+    Only,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArchiveBy {
+    Month,
+    Year,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Unmatched {
+    #[default]
+    Inbox,
+    Drop,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EmptyBody {
+    Drop,
+    Folder(String),
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Client {
@@ -120,16 +767,108 @@ pub struct Client {
     pub command: Vec<String>,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHours {
+    /// Local time (`HH:MM`) at which the quiet hours start.
+    #[serde(deserialize_with = "deserialize_time")]
+    pub start: NaiveTime,
+
+    /// Local time (`HH:MM`) at which the quiet hours end.
+    ///
+    /// May be before [`start`](Self::start), in which case the quiet hours span midnight.
+    #[serde(deserialize_with = "deserialize_time")]
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    /// Are we currently in the quiet hours?
+    pub fn active(&self) -> bool {
+        let now = Local::now().time();
+        if self.start <= self.end {
+            self.start <= now && now < self.end
+        } else {
+            self.start <= now || now < self.end
+        }
+    }
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M").map_err(<D::Error as serde::de::Error>::custom)
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct Git {
     /// Should `git push` be run if any new commits have been created?
+    ///
+    /// If the push is rejected, the local commits are rebased onto the remote ones and the push is
+    /// tried once more.
     #[serde(default)]
     pub push: bool,
 
+    /// Push even if no new commits have been created, e.g. to push the commits of a previous run
+    /// whose push failed. Requires `push`.
+    #[serde(rename = "push-always", default)]
+    pub push_always: bool,
+
     /// Should `git pull` be run before updating the mails?
     #[serde(default)]
     pub pull: bool,
+
+    /// Should `git pull` rebase the local commits instead of merging?
+    ///
+    /// If not specified, the `pull.rebase` setting of git is used. If pulling runs into conflicts,
+    /// the rebase or merge is aborted and `lkml` stops.
+    #[serde(rename = "pull-rebase")]
+    pub pull_rebase: Option<bool>,
+
+    /// Remote to push to and pull from instead of the upstream of the current branch.
+    pub remote: Option<String>,
+
+    /// Branch of [`remote`](Self::remote) to push to and pull from, requires `remote`.
+    pub branch: Option<String>,
+
+    /// Stash uncommitted changes (e.g. flags changed by the mail client) instead of refusing to
+    /// update the mails.
+    ///
+    /// The changes are reapplied after the update. If that runs into conflicts, `lkml` stops
+    /// before committing, leaving the conflicts in the store to be resolved manually and the
+    /// changes in `git stash`.
+    #[serde(default)]
+    pub stash: bool,
+
+    /// Add the number of new mails per folder as `Lkml-Folder: <folder>=<count>` trailers to the
+    /// update commits.
+    #[serde(rename = "folder-trailers", default)]
+    pub folder_trailers: bool,
+
+    /// Name to use for the commits instead of the configured `user.name`.
+    #[serde(rename = "author-name")]
+    pub author_name: Option<String>,
+
+    /// Email address to use for the commits instead of the configured `user.email`.
+    #[serde(rename = "author-email")]
+    pub author_email: Option<String>,
+
+    /// Sign the commits with the GPG key configured in git (`user.signingkey`).
+    #[serde(default)]
+    pub sign: bool,
+
+    /// Sign the commits with this GPG key instead, implies `sign`.
+    #[serde(rename = "signing-key")]
+    pub signing_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Notmuch {
+    /// Path of the notmuch config file, if not the default one.
+    pub config: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Debug, Default)]
@@ -157,6 +896,32 @@ pub struct Ignore {
     /// lists = ["<qemu-devel.nongnu.org>"]
     /// ```
     pub lists: HashSet<String>,
+
+    /// Set of regular expressions, emails whose subject matches one of them are ignored like
+    /// the ones from [`lists`](Self::lists).
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// subject-patterns = ["^\\[PATCH AUTOSEL"]
+    /// ```
+    #[serde(rename = "subject-patterns", default)]
+    pub subject_patterns: HashSet<Keyword>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Block {
+    /// Set of mail addresses to drop all mails from, compared case-insensitively.
+    ///
+    /// This takes precedence over all other rules, including threads.
+    ///
+    /// # Examples
+    ///
+    /// ```toml
+    /// addresses = ["spammer@example.org"]
+    /// ```
+    pub addresses: HashSet<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -166,6 +931,21 @@ impl Keyword {
     pub fn matches(&self, text: &str) -> bool {
         self.0.is_match(text)
     }
+
+    /// Returns this keyword matching regardless of case.
+    fn case_insensitive(&self) -> Self {
+        let regex = RegexBuilder::new(self.0.as_str())
+            .case_insensitive(true)
+            .build()
+            .expect("the regex already compiled");
+        Keyword(regex)
+    }
+}
+
+impl fmt::Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.as_str())
+    }
 }
 
 impl PartialEq for Keyword {
@@ -202,14 +982,240 @@ pub enum Error {
     Read(io::Error, PathBuf),
     #[error("failed to parse config file `{1}`: {0}")]
     Parse(toml::de::Error, PathBuf),
+    #[error("`remote-url` in config file `{0}` must be a string")]
+    RemoteUrl(PathBuf),
+    #[error("failed to download remote config `{1}` and there is no cached copy: {0}")]
+    Remote(String, String),
+    #[error("failed to parse remote config `{1}`: {0}")]
+    RemoteParse(toml::de::Error, String),
+    #[error(
+        "`path` points to the maildir subfolder `{0}`, it should point to the root maildir instead"
+    )]
+    Subfolder(PathBuf),
+    #[error("unknown `default-charset` `{0}`")]
+    Charset(String),
+    #[error("`inbox` must be an `http://` or `https://` URL ending with `/`, got `{0}`")]
+    Inbox(String),
+    #[error("no `query`, `queries` or `saved-search` configured")]
+    NoQuery,
+    #[error("`git.branch` requires `git.remote` to be set")]
+    GitBranch,
+    #[error("folder `{0}` uses `unread-on-mention`, but `ignore.name` is not configured")]
+    MentionName(String),
+    #[error(
+        "invalid `set-flags` `{1}` of folder `{0}`, expected any of `D`, `F`, `P`, `R`, `S` and `T`"
+    )]
+    SetFlags(String, String),
 }
 
+/// Loads the config from `~/.config/lkml/config.toml`.
 pub fn load() -> Result<Config, Error> {
     let path = BaseDirs::new()
         .ok_or(Error::NoHome)?
         .config_dir()
         .join("lkml")
         .join("config.toml");
+    load_from(&path)
+}
+
+/// Loads the config from the file at `path`.
+pub fn load_from(path: &Path) -> Result<Config, Error> {
+    let path = path.to_owned();
     let cfg = fs::read_to_string(&path).map_err(|e| Error::Read(e, path.clone()))?;
-    toml::from_str(&cfg).map_err(|e| Error::Parse(e, path.clone()))
+    let mut cfg: toml::Table = toml::from_str(&cfg).map_err(|e| Error::Parse(e, path.clone()))?;
+    // not a field of `Config`, since the remote config is merged before deserializing it.
+    if let Some(url) = cfg.remove("remote-url") {
+        let url = url
+            .as_str()
+            .ok_or_else(|| Error::RemoteUrl(path.clone()))?
+            .to_owned();
+        let remote = fetch_remote(&url)?;
+        let remote: toml::Table =
+            toml::from_str(&remote).map_err(|e| Error::RemoteParse(e, url.clone()))?;
+        merge_remote(&mut cfg, remote, &url);
+    }
+    let mut cfg: Config = toml::Value::Table(cfg)
+        .try_into()
+        .map_err(|e| Error::Parse(e, path.clone()))?;
+    if cfg.case_insensitive {
+        // the keywords are deserialized without access to the rest of the config.
+        let ignore_case = |kws: &mut HashSet<Keyword>| {
+            *kws = kws.iter().map(Keyword::case_insensitive).collect();
+        };
+        ignore_case(&mut cfg.flagging.keywords);
+        if let Some(ignore) = &mut cfg.ignore {
+            ignore_case(&mut ignore.subject_patterns);
+        }
+        for folder in &mut cfg.folders {
+            ignore_case(&mut folder.keywords);
+            ignore_case(&mut folder.exclude_keywords);
+            ignore_case(&mut folder.subject_keywords);
+            ignore_case(&mut folder.trailers);
+            ignore_case(&mut folder.from_name);
+            ignore_case(&mut folder.from_keywords);
+            if let Some(kws) = &mut folder.flagging_keywords {
+                ignore_case(kws);
+            }
+        }
+    }
+    // Maildir++ subfolders are marked with a `maildirfolder` file, our folders would end up nested
+    // inside of it.
+    if cfg.path.join("maildirfolder").exists() {
+        return Err(Error::Subfolder(cfg.path));
+    }
+    for inbox in std::iter::once(&cfg.inbox).chain(cfg.queries.iter().flat_map(|q| &q.inbox)) {
+        if !(inbox.starts_with("https://") || inbox.starts_with("http://")) || !inbox.ends_with('/')
+        {
+            return Err(Error::Inbox(inbox.clone()));
+        }
+    }
+    if cfg.query.is_none() && cfg.queries.is_empty() && cfg.saved_search.is_none() {
+        return Err(Error::NoQuery);
+    }
+    if cfg
+        .git
+        .as_ref()
+        .is_some_and(|git| git.branch.is_some() && git.remote.is_none())
+    {
+        return Err(Error::GitBranch);
+    }
+    if cfg.ignore.is_none() {
+        if let Some(folder) = cfg.folders.iter().find(|f| f.unread_on_mention) {
+            return Err(Error::MentionName(folder.name.clone()));
+        }
+    }
+    if let Some(folder) = cfg
+        .folders
+        .iter()
+        .find(|f| !f.set_flags.chars().all(|flag| MAILDIR_FLAGS.contains(flag)))
+    {
+        return Err(Error::SetFlags(
+            folder.name.clone(),
+            folder.set_flags.clone(),
+        ));
+    }
+    if let Some(charset) = &cfg.default_charset {
+        if Encoding::for_label(charset.as_bytes()).is_none() {
+            return Err(Error::Charset(charset.clone()));
+        }
+    }
+    Ok(cfg)
+}
+
+/// Downloads the remote config at `url`, falling back to the cached copy.
+fn fetch_remote(url: &str) -> Result<String, Error> {
+    let dir = BaseDirs::new()
+        .ok_or(Error::NoHome)?
+        .cache_dir()
+        .join("lkml");
+    // keyed by the url, so switching configs never serves the cache of another one.
+    let name = format!("remote-{:016x}", fnv1a(url.as_bytes()));
+    let cache = dir.join(format!("{name}.toml"));
+    let download = dir.join(format!("{name}.toml.new"));
+    let etag = dir.join(format!("{name}.etag"));
+    let res = fs::create_dir_all(&dir)
+        .map_err(|e| e.to_string())
+        .and_then(|()| curl(url, &download, &etag));
+    match res {
+        // on `304 Not Modified`, nothing is written.
+        Ok(()) if fs::metadata(&download).is_ok_and(|m| m.len() > 0) => {
+            if let Err(e) = fs::rename(&download, &cache) {
+                warn!("could not cache remote config: {e}");
+            }
+        }
+        Ok(()) => {}
+        Err(e) => warn!("could not download remote config `{url}`, using the cached copy: {e}"),
+    }
+    let _ = fs::remove_file(&download);
+    fs::read_to_string(&cache).map_err(|e| Error::Remote(e.to_string(), url.to_owned()))
+}
+
+fn curl(url: &str, output: &Path, etag: &Path) -> Result<(), String> {
+    let mut cmd = Command::new("curl");
+    cmd.args(["--silent", "--show-error", "--fail", "--location"])
+        .args(["--max-time", "30"])
+        .arg("--etag-save")
+        .arg(etag)
+        .arg("--output")
+        .arg(output);
+    if fs::exists(etag).unwrap_or(false) {
+        cmd.arg("--etag-compare").arg(etag);
+    }
+    let out = cmd.arg(url).output().map_err(|e| e.to_string())?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&out.stderr).trim().to_owned())
+    }
+}
+
+/// Adds the `query` and `folders` of the `remote` config to the local config `cfg`, unless they
+/// are overridden locally.
+fn merge_remote(cfg: &mut toml::Table, remote: toml::Table, url: &str) {
+    for (key, value) in remote {
+        match (key.as_str(), value) {
+            ("query", query) => {
+                cfg.entry("query").or_insert(query);
+            }
+            ("queries", toml::Value::Array(queries)) => {
+                let local = cfg
+                    .entry("queries")
+                    .or_insert_with(|| toml::Value::Array(Vec::new()));
+                if let Some(local) = local.as_array_mut() {
+                    local.extend(queries);
+                }
+            }
+            ("folders", toml::Value::Array(folders)) => {
+                let local = cfg
+                    .entry("folders")
+                    .or_insert_with(|| toml::Value::Array(Vec::new()));
+                let Some(local) = local.as_array_mut() else {
+                    continue;
+                };
+                for folder in folders {
+                    let name = folder.get("name").cloned();
+                    if !local.iter().any(|f| f.get("name") == name.as_ref()) {
+                        local.push(folder);
+                    }
+                }
+            }
+            (key, _) => warn!("ignoring `{key}` in remote config `{url}`"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    /// Parses the config with the options and folders in `config`.
+    fn config(config: &str) -> Config {
+        toml::from_str(&format!("path = \"/tmp\"\naddresses = []\n{config}")).unwrap()
+    }
+
+    #[test]
+    fn quarantine_folder_without_rules() {
+        let cfg = config(
+            "quarantine-folder = \"errors\"\n\
+             [[folders]]\n\
+             name = \"errors\"\n\
+             priority = 0\n",
+        );
+        assert!(cfg.problems().is_empty());
+    }
+
+    #[test]
+    fn shadowed_keyword() {
+        let folders = "[[folders]]\n\
+            name = \"rust\"\n\
+            keywords = [\"rust/\"]\n\
+            priority = 10\n\
+            [[folders]]\n\
+            name = \"all\"\n\
+            keywords = [\"rust/\"]\n\
+            priority = 0\n";
+        assert_eq!(config(folders).problems().len(), 1);
+        let cross_file = config(&format!("cross-file = true\n{folders}"));
+        assert!(cross_file.problems().is_empty());
+    }
 }
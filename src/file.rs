@@ -0,0 +1,15 @@
+use std::{fs, io, path::Path};
+
+/// Moves the file `from` to `to`.
+///
+/// Falls back to copying and removing the file when they are on different filesystems, e.g. when
+/// the mails were downloaded into a temporary directory.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)
+        }
+        res => res,
+    }
+}
@@ -1,6 +1,13 @@
-use std::{io, path::Path, process::Command};
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::config;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -10,6 +17,15 @@ pub enum Error {
     Code(i32),
     #[error("`git` execution unexpectedly terminated by signal.")]
     Signal,
+    #[error("`git pull` ran into conflicts and was aborted, please reconcile the store manually.")]
+    Conflict,
+    #[error(
+        "the stashed changes conflict with the update, please resolve the conflicts in the store \
+        manually; the changes are still kept in `git stash`."
+    )]
+    StashConflict,
+    #[error("could not sign the commit: {0}")]
+    Sign(String),
 }
 
 type Result<T = ()> = core::result::Result<T, Error>;
@@ -27,16 +43,128 @@ pub fn add(dir: impl AsRef<Path>) -> Result {
     git(["add", "."], dir)
 }
 
-pub fn commit(message: &str, dir: impl AsRef<Path>) -> Result {
-    git(["commit", "-m", message], dir)
+/// Commits the staged changes, `message` may span multiple lines.
+///
+/// The commit is signed if [`config::Git::sign`] or [`config::Git::signing_key`] is set.
+pub fn commit(message: &str, cfg: &config::Git, dir: impl AsRef<Path>) -> Result {
+    let mut args = Vec::new();
+    if let Some(name) = &cfg.author_name {
+        args.extend(["-c".to_owned(), format!("user.name={name}")]);
+    }
+    if let Some(email) = &cfg.author_email {
+        args.extend(["-c".to_owned(), format!("user.email={email}")]);
+    }
+    args.extend(["commit", "-m", message].map(str::to_owned));
+    let sign = match &cfg.signing_key {
+        Some(key) => format!("--gpg-sign={key}"),
+        None if cfg.sign => "--gpg-sign".to_owned(),
+        None => return git(args.iter().map(String::as_str), dir),
+    };
+    args.push(sign);
+    // capture the output to tell signing failures apart from other ones.
+    let res = Command::new("git").args(&args).current_dir(dir).output()?;
+    io::stdout().write_all(&res.stdout)?;
+    if res.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&res.stderr);
+    if stderr.contains("failed to sign") {
+        return Err(Error::Sign(stderr.trim().to_owned()));
+    }
+    io::stderr().write_all(&res.stderr)?;
+    Err(res.status.code().map(Error::Code).unwrap_or(Error::Signal))
+}
+
+/// Runs `git push`.
+///
+/// If the push is rejected (e.g. because another machine pushed in the meantime), the local commits
+/// are rebased onto the remote ones and the push is tried once more.
+pub fn push(cfg: &config::Git, dir: impl AsRef<Path>) -> Result {
+    let dir = dir.as_ref();
+    let push = || git(["push"].into_iter().chain(target(cfg)), dir);
+    if let Err(err) = push() {
+        warn!("`git push` failed ({err}), rebasing onto the remote and trying again");
+        pull_with(Some("--rebase"), cfg, dir)?;
+        push()?;
+    }
+    info!("pushed the store");
+    Ok(())
+}
+
+/// Returns the [`config::Git::remote`] and [`config::Git::branch`] to push to and pull from.
+///
+/// Without them, git uses the upstream of the current branch.
+fn target(cfg: &config::Git) -> impl Iterator<Item = &str> {
+    cfg.remote.iter().chain(&cfg.branch).map(String::as_str)
 }
 
-pub fn push(dir: impl AsRef<Path>) -> Result {
-    git(["push"], dir)
+/// Runs `git pull`, aborting the merge or rebase if it fails with conflicts.
+pub fn pull(cfg: &config::Git, dir: impl AsRef<Path>) -> Result {
+    let mode = cfg
+        .pull_rebase
+        .map(|rebase| if rebase { "--rebase" } else { "--no-rebase" });
+    pull_with(mode, cfg, dir.as_ref())
 }
 
-pub fn pull(dir: impl AsRef<Path>) -> Result {
-    git(["pull"], dir)
+/// Runs `git pull` with the `mode` flag, if any, see [`pull`].
+fn pull_with(mode: Option<&str>, cfg: &config::Git, dir: &Path) -> Result {
+    let args = ["pull"].into_iter().chain(mode).chain(target(cfg));
+    let err = match git(args, dir) {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    for (marker, abort) in [
+        ("rebase-merge", "rebase"),
+        ("rebase-apply", "rebase"),
+        ("MERGE_HEAD", "merge"),
+    ] {
+        if git_path(marker, dir)?.exists() {
+            git([abort, "--abort"], dir)?;
+            return Err(Error::Conflict);
+        }
+    }
+    Err(err)
+}
+
+/// Stashes all changes, including untracked files (e.g. mails moved by the mail client).
+pub fn stash(dir: impl AsRef<Path>) -> Result {
+    git(["stash", "push", "--include-untracked"], dir)
+}
+
+/// Reapplies the changes stashed by [`stash`].
+///
+/// If they conflict, the working tree is left as git leaves it and the changes stay in the stash.
+pub fn stash_pop(dir: impl AsRef<Path>) -> Result {
+    // capture the output to tell conflicts apart from other failures.
+    let res = Command::new("git")
+        .args(["stash", "pop"])
+        .current_dir(dir)
+        .output()?;
+    io::stdout().write_all(&res.stdout)?;
+    io::stderr().write_all(&res.stderr)?;
+    if res.status.success() {
+        return Ok(());
+    }
+    let conflict = [&res.stdout, &res.stderr]
+        .iter()
+        .any(|out| String::from_utf8_lossy(out).contains("CONFLICT"));
+    if conflict {
+        return Err(Error::StashConflict);
+    }
+    Err(res.status.code().map(Error::Code).unwrap_or(Error::Signal))
+}
+
+/// Returns the path of `name` inside of the `.git` directory of the repository at `dir`.
+fn git_path(name: &str, dir: &Path) -> Result<PathBuf> {
+    let res = Command::new("git")
+        .args(["rev-parse", "--git-path", name])
+        .current_dir(dir)
+        .output()?;
+    if res.status.success() {
+        Ok(dir.join(String::from_utf8_lossy(&res.stdout).trim()))
+    } else {
+        Err(res.status.code().map(Error::Code).unwrap_or(Error::Signal))
+    }
 }
 
 pub fn is_clean(dir: impl AsRef<Path>) -> Result<bool> {
@@ -51,3 +179,70 @@ pub fn is_clean(dir: impl AsRef<Path>) -> Result<bool> {
         Err(res.status.code().map(Error::Code).unwrap_or(Error::Signal))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, process::Command};
+
+    use tempdir::TempDir;
+
+    use super::{add, commit, pull, push};
+    use crate::config;
+
+    /// Runs `git` with `args` in `dir` and returns whether it succeeded.
+    fn git(dir: &Path, args: &[&str]) -> bool {
+        Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args([
+                "-c",
+                "commit.gpgsign=false",
+                "-c",
+                "init.defaultBranch=main",
+            ])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap()
+            .status
+            .success()
+    }
+
+    /// Returns the config pushing to and pulling from the `main` branch of `remote`.
+    fn cfg(remote: &str) -> config::Git {
+        toml::from_str(&format!(
+            "remote = \"{remote}\"\n\
+             branch = \"main\"\n\
+             author-name = \"test\"\n\
+             author-email = \"test@example.com\"\n"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn two_remotes() {
+        let dir = TempDir::new("lkml-git").unwrap();
+        let dir = dir.path();
+        let work = dir.join("work");
+        assert!(git(dir, &["init", "--bare", "a.git"]));
+        assert!(git(dir, &["init", "--bare", "b.git"]));
+        assert!(git(dir, &["init", "work"]));
+        assert!(git(&work, &["config", "commit.gpgsign", "false"]));
+        assert!(git(&work, &["remote", "add", "a", "../a.git"]));
+        assert!(git(&work, &["remote", "add", "b", "../b.git"]));
+
+        fs::write(work.join("mail"), "first").unwrap();
+        add(&work).unwrap();
+        commit("first", &cfg("b"), &work).unwrap();
+        push(&cfg("b"), &work).unwrap();
+        assert!(git(&dir.join("b.git"), &["rev-parse", "--verify", "main"]));
+        assert!(!git(&dir.join("a.git"), &["rev-parse", "--verify", "main"]));
+
+        assert!(git(dir, &["clone", "b.git", "other"]));
+        fs::write(dir.join("other").join("mail"), "second").unwrap();
+        assert!(git(&dir.join("other"), &["commit", "-am", "second"]));
+        assert!(git(&dir.join("other"), &["push", "origin", "main"]));
+        assert!(pull(&cfg("a"), &work).is_err());
+        pull(&cfg("b"), &work).unwrap();
+        assert_eq!(fs::read_to_string(work.join("mail")).unwrap(), "second");
+    }
+}
@@ -0,0 +1,9 @@
+/// Returns the 64 bit FNV-1a hash of `bytes`.
+///
+/// Unlike `DefaultHasher`, this is stable across Rust releases, so it can be used for ids and file
+/// names that have to stay the same between runs.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -1,8 +1,17 @@
-use std::{io, process::Command};
+use std::{
+    fmt, fs, io,
+    path::Path,
+    process::{Command, ExitStatus},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
 
-use clap::ValueEnum;
 use tempdir::TempDir;
 use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::{file::move_file, hash::fnv1a};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -12,11 +21,20 @@ pub enum Error {
     Code(i32),
     #[error("`lei` execution unexpectedly terminated by signal.")]
     Signal,
+    #[error("`lei` did not finish within {0}s and was killed")]
+    Timeout(u64),
+    #[error("could not take the downloaded mails: {0}")]
+    Take(io::Error),
+    #[error("could not use the download cache: {0}")]
+    Cache(io::Error),
 }
 
 type Result<T = ()> = core::result::Result<T, Error>;
 
-#[derive(ValueEnum, Clone, Copy, Debug)]
+/// The public-inbox that is queried if no other is configured.
+pub const DEFAULT_INBOX: &str = "https://lore.kernel.org/all/";
+
+#[derive(Clone, Copy, Debug)]
 pub enum Interval {
     /// Searches mails up to 2 days ago.
     Day,
@@ -26,31 +44,308 @@ pub enum Interval {
     Month,
     /// Searches mails up to 1 year ago.
     Year,
+    /// Searches mails up to the given number of units ago.
+    Ago(u32, Unit),
+    /// Searches mails since the last completed run, see [`Interval::resolve`].
+    SinceLast,
+}
+
+/// What to search for with [`query`].
+#[derive(Clone, Copy, Debug)]
+pub struct Search {
+    /// How far back to search.
+    pub interval: Interval,
+    /// Maximum number of mails to download per query.
+    pub limit: Option<usize>,
+}
+
+/// How `lei` is run.
+#[derive(Clone, Copy, Debug)]
+pub struct Exec {
+    /// Number of times a failed `lei` is run again.
+    pub retries: u32,
+    /// Time after which `lei` is killed, the attempt counts as failed.
+    pub timeout: Option<Duration>,
+    /// Suppress the progress messages of `lei`.
+    pub quiet: bool,
+}
+
+/// Directory in which the results of [`query`] are kept to avoid downloading them again.
+#[derive(Clone, Copy, Debug)]
+pub struct Cache<'a> {
+    pub dir: &'a Path,
+    /// How long the cached results are used.
+    pub ttl: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Unit {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+#[derive(Debug, Error)]
+#[error(
+    "invalid interval `{0}`, expected `day`, `week`, `month`, `year`, `last` or a number followed \
+    by `h`, `d`, `w`, `m` or `y` (e.g. `10d` or `36h`)"
+)]
+pub struct ParseIntervalError(String);
+
+impl FromStr for Interval {
+    type Err = ParseIntervalError;
+
+    fn from_str(s: &str) -> core::result::Result<Self, Self::Err> {
+        let err = || ParseIntervalError(s.to_owned());
+        match s {
+            "day" => return Ok(Interval::Day),
+            "week" => return Ok(Interval::Week),
+            "month" => return Ok(Interval::Month),
+            "year" => return Ok(Interval::Year),
+            "last" => return Ok(Interval::SinceLast),
+            _ => {}
+        }
+        let (count, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).ok_or_else(err)?);
+        let unit = match unit {
+            "h" => Unit::Hour,
+            "d" => Unit::Day,
+            "w" => Unit::Week,
+            "m" => Unit::Month,
+            "y" => Unit::Year,
+            _ => return Err(err()),
+        };
+        Ok(Interval::Ago(count.parse().map_err(|_| err())?, unit))
+    }
 }
 
-pub fn query(interval: Interval, query: &str) -> Result<TempDir> {
-    let interval = match interval {
-        Interval::Day => "2.day.ago",
-        Interval::Week => "2.week.ago",
-        Interval::Month => "3.month.ago",
-        Interval::Year => "1.year.ago",
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (count, unit) = match *self {
+            Interval::SinceLast => return write!(f, "the time since the last run"),
+            Interval::Day => (2, Unit::Day),
+            Interval::Week => (2, Unit::Week),
+            Interval::Month => (3, Unit::Month),
+            Interval::Year => (1, Unit::Year),
+            Interval::Ago(count, unit) => (count, unit),
+        };
+        let unit = match unit {
+            Unit::Hour => "hour",
+            Unit::Day => "day",
+            Unit::Week => "week",
+            Unit::Month => "month",
+            Unit::Year => "year",
+        };
+        if count == 1 {
+            write!(f, "the last {unit}")
+        } else {
+            write!(f, "the last {count} {unit}s")
+        }
+    }
+}
+
+impl Interval {
+    /// Replaces [`Interval::SinceLast`] with the hours since the last run (one more to not miss
+    /// any mails), or two weeks if there was none.
+    pub fn resolve(self, since_last_run: Option<Duration>) -> Interval {
+        match (self, since_last_run) {
+            (Interval::SinceLast, Some(since)) => {
+                let hours = since.as_secs().div_ceil(3600) + 1;
+                Interval::Ago(u32::try_from(hours).unwrap_or(u32::MAX), Unit::Hour)
+            }
+            (Interval::SinceLast, None) => Interval::Week,
+            (interval, _) => interval,
+        }
+    }
+
+    /// Returns the start of the interval as understood by `lei`'s `rt:` prefix.
+    fn lei_range(self) -> String {
+        match self {
+            Interval::Day => "2.day.ago".to_owned(),
+            Interval::Week => "2.week.ago".to_owned(),
+            Interval::Month => "3.month.ago".to_owned(),
+            Interval::Year => "1.year.ago".to_owned(),
+            Interval::Ago(count, unit) => {
+                let unit = match unit {
+                    Unit::Hour => "hour",
+                    Unit::Day => "day",
+                    Unit::Week => "week",
+                    Unit::Month => "month",
+                    Unit::Year => "year",
+                };
+                format!("{count}.{unit}.ago")
+            }
+            // not resolved, e.g. with `--print-query`.
+            Interval::SinceLast => Interval::Week.lei_range(),
+        }
+    }
+}
+
+/// Runs `cmd`, retrying it up to `exec.retries` times if it fails or times out.
+fn run(cmd: &mut Command, exec: Exec) -> Result {
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        let err = match wait(cmd, exec.timeout)? {
+            Some(res) if res.success() => return Ok(()),
+            // a signal usually means that the user wants to stop.
+            Some(res) => res.code().map(Error::Code).ok_or(Error::Signal)?,
+            None => Error::Timeout(exec.timeout.unwrap_or_default().as_secs()),
+        };
+        if attempt == exec.retries {
+            return Err(err);
+        }
+        attempt += 1;
+        warn!(
+            "{err}, retrying in {}s ({attempt}/{})",
+            delay.as_secs(),
+            exec.retries
+        );
+        thread::sleep(delay);
+        delay *= 2;
+    }
+}
+
+/// Runs `cmd` to completion, or kills it and returns `None` if it takes longer than `timeout`.
+fn wait(cmd: &mut Command, timeout: Option<Duration>) -> Result<Option<ExitStatus>> {
+    let Some(timeout) = timeout else {
+        return Ok(Some(cmd.status()?));
     };
+    let start = Instant::now();
+    let mut child = cmd.spawn()?;
+    loop {
+        if let Some(res) = child.try_wait()? {
+            return Ok(Some(res));
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Returns the query that is sent to `inbox` for `query`, restricted to the interval of `search`.
+pub fn query_string(search: Search, query: &str) -> String {
+    format!("({query}) AND rt:{}..", search.interval.lei_range())
+}
+
+/// Runs all `(inbox, query)` pairs in `queries` and collects their mails in one maildir.
+///
+/// With a `cache`, queries that were run within its TTL are not run again, their cached mails
+/// are used instead.
+pub fn query(
+    search: Search,
+    queries: &[(&str, &str)],
+    exec: Exec,
+    cache: Option<Cache<'_>>,
+) -> Result<TempDir> {
     let tmpdir = TempDir::new("lkml-lei")?;
-    let res = Command::new("lei")
-        .arg("q")
-        .args([
-            // don't store the query, as we're storing it in our config.
-            "--no-save",
-            // get all emails from the thread where a single one has matched.
-            "--threads",
-            "--include=https://lore.kernel.org/all",
-        ])
-        .arg(format!("--output={}", tmpdir.path().display()))
-        .arg(format!("({query}) AND rt:{interval}.."))
-        .status()?;
-    if !res.success() {
-        Err(res.code().map(Error::Code).unwrap_or(Error::Signal))
-    } else {
-        Ok(tmpdir)
+    for sub in ["new", "cur", "tmp"] {
+        fs::create_dir(tmpdir.path().join(sub)).map_err(Error::Take)?;
+    }
+    for (i, (inbox, query)) in queries.iter().enumerate() {
+        let query = query_string(search, query);
+        let cached = cache.map(|cache| {
+            let key = format!("{inbox}\0{query}\0{:?}", search.limit);
+            (
+                cache.dir.join(format!("{:016x}", fnv1a(key.as_bytes()))),
+                cache.ttl,
+            )
+        });
+        if let Some((cached, ttl)) = &cached {
+            if is_fresh(cached, *ttl).map_err(Error::Cache)? {
+                info!("using the cached mails of `{query}`");
+                copy(cached, tmpdir.path()).map_err(Error::Cache)?;
+                continue;
+            }
+        }
+        let output = tmpdir.path().join(format!("query-{i}"));
+        let mut cmd = Command::new("lei");
+        cmd.arg("q")
+            .args([
+                // don't store the query, as we're storing it in our config.
+                "--no-save",
+                // get all emails from the thread where a single one has matched.
+                "--threads",
+            ])
+            .arg(format!("--include={inbox}"))
+            .arg(format!("--output={}", output.display()));
+        if let Some(limit) = search.limit {
+            cmd.arg(format!("--limit={limit}"));
+        }
+        if exec.quiet {
+            cmd.arg("--quiet");
+        }
+        cmd.arg(query);
+        run(&mut cmd, exec)?;
+        if let Some((cached, _)) = &cached {
+            match fs::remove_dir_all(cached) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(Error::Cache(e)),
+                _ => {}
+            }
+            copy(&output, cached).map_err(Error::Cache)?;
+        }
+        take(&output, tmpdir.path())?;
+        fs::remove_dir_all(&output).map_err(Error::Take)?;
+    }
+    Ok(tmpdir)
+}
+
+/// Returns whether the cached maildir `dir` exists and was written less than `ttl` ago.
+fn is_fresh(dir: &Path, ttl: Duration) -> io::Result<bool> {
+    let modified = match fs::metadata(dir) {
+        Ok(meta) => meta.modified()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    // a modification time in the future counts as fresh.
+    Ok(modified.elapsed().map_or(true, |age| age < ttl))
+}
+
+/// Copies the mails of the maildir `from` into the maildir `to`, creating it if needed.
+fn copy(from: &Path, to: &Path) -> io::Result<()> {
+    for sub in ["new", "cur"] {
+        fs::create_dir_all(to.join(sub))?;
+        for entry in fs::read_dir(from.join(sub))? {
+            let from = entry?.path();
+            if let Some(name) = from.file_name() {
+                fs::copy(&from, to.join(sub).join(name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Updates the `lei` saved search writing to the maildir `output` and takes the mails out of it.
+pub fn update(output: &Path, exec: Exec) -> Result<TempDir> {
+    let mut cmd = Command::new("lei");
+    cmd.arg("up");
+    if exec.quiet {
+        cmd.arg("--quiet");
+    }
+    run(cmd.arg(output), exec)?;
+    let tmpdir = TempDir::new("lkml-lei")?;
+    for sub in ["new", "cur", "tmp"] {
+        fs::create_dir(tmpdir.path().join(sub)).map_err(Error::Take)?;
+    }
+    take(output, tmpdir.path())?;
+    Ok(tmpdir)
+}
+
+/// Moves the mails of the maildir `from` into the maildir `to`.
+pub fn take(from: &Path, to: &Path) -> Result {
+    for sub in ["new", "cur"] {
+        for entry in fs::read_dir(from.join(sub)).map_err(Error::Take)? {
+            let from = entry.map_err(Error::Take)?.path();
+            let Some(name) = from.file_name() else {
+                continue;
+            };
+            let to = to.join(sub).join(name);
+            move_file(&from, &to).map_err(Error::Take)?;
+        }
     }
+    Ok(())
 }
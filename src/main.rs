@@ -14,82 +14,728 @@
 //!
 //! See [`Config`] for the various configuration options. The location of the config file is
 //! `~/.config/lkml/config.toml` on linux.
+//!
+//! ## IMAP
+//!
+//! `lkml` only delivers into a local maildir. To read the mails via IMAP, synchronize the store
+//! with the server using a tool like `mbsync` or `offlineimap` after every run, the folders map to
+//! IMAP mailboxes and the read and flagged marks to the `\Seen` and `\Flagged` flags.
 
 use std::{
     io,
-    path::Path,
+    path::{Path, PathBuf},
     process::{Command, ExitCode},
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, SystemTime},
 };
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Result, bail};
+use clap::{ArgAction, Parser, Subcommand};
 use maildir::Maildir;
+use tempdir::TempDir;
 use thiserror::Error;
-use tracing::debug;
-use tracing_subscriber::{filter::EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::{debug, error, info};
+use tracing_subscriber::{
+    filter::{EnvFilter, LevelFilter},
+    fmt::format::FmtSpan,
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
 
-use crate::{config::Config, lei::Interval};
+use crate::{
+    config::Config,
+    lei::{Interval, Search},
+};
 
 mod assort;
 mod config;
+mod file;
 mod git;
+mod hash;
 mod lei;
+mod state;
+
+/// Version information for bug reports.
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("LKML_COMMIT"),
+    "\nbackends: lei",
+);
 
 #[derive(Parser, Debug)]
+#[command(version, long_version = LONG_VERSION, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// The amount of time to scan back
+    #[command(subcommand)]
+    command: Option<Cmd>,
+
+    /// Use this config file instead of `~/.config/lkml/config.toml`.
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+
+    /// The amount of time to scan back.
+    ///
+    /// Either `day` (2 days), `week` (2 weeks), `month` (3 months), `year` (1 year) or a number
+    /// followed by `h`, `d`, `w`, `m` or `y` for hours, days, weeks, months or years, e.g. `10d`.
+    /// `last` scans back to the last completed run (2 weeks if there was none). Defaults to `day`.
     interval: Option<Interval>,
+
+    /// Download at most this many mails per query.
+    ///
+    /// `lei` returns the same mails for the same query on every run, so this cannot be used to
+    /// page through a big backlog. Threads are only assorted with the downloaded mails, so a
+    /// limited run might split them across folders.
+    /// Cannot be used with `saved-search`.
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Print the queries that would be sent to `lei` (including the interval) and exit.
+    ///
+    /// With multiple `queries`, every query is prefixed by its inbox.
+    #[arg(long)]
+    print_query: bool,
+
+    /// Only index the downloaded mails, skip reading the mails already stored in the folders.
+    ///
+    /// This is a lot faster for big mailboxes, but replies to already stored mails are no longer
+    /// put into the folder of their thread and are assorted by their content only.
+    #[arg(long, visible_alias = "no-existing", global = true)]
+    only_new: bool,
+
+    /// Append the subject and the start of the body of every mail that did not match any folder
+    /// to this file, to help with finding new keywords.
+    #[arg(long, value_name = "PATH", global = true)]
+    dump_unmatched: Option<PathBuf>,
+
+    /// Number of body lines to write with `--dump-unmatched`.
+    #[arg(
+        long,
+        value_name = "LINES",
+        default_value_t = 20,
+        requires = "dump_unmatched",
+        global = true
+    )]
+    dump_unmatched_lines: usize,
+
+    /// Print which mails were dropped as duplicates and why.
+    ///
+    /// Shows the paths of the dropped and the kept copies, the strategy that matched and how much
+    /// their bodies differ.
+    #[arg(long, global = true)]
+    explain_dedup: bool,
+
+    /// Write a JSON report of the run to this file, with the number of new, delivered (per
+    /// folder), deleted (per reason), read and flagged mails.
+    #[arg(long, value_name = "PATH", global = true)]
+    report: Option<PathBuf>,
+
+    /// Number of threads used to parse mails, overrides `parse-concurrency` from the config.
+    #[arg(long, value_name = "THREADS", global = true)]
+    parse_concurrency: Option<usize>,
+
+    /// Write the index of all mails as JSON to this file instead of assorting them.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["headers_only", "dry_run"])]
+    dump_index: Option<PathBuf>,
+
+    /// Only print where the downloaded mails would go, without moving them.
+    ///
+    /// This is faster, since the bodies are not decoded, but keywords and trailers never match.
+    #[arg(long, global = true)]
+    headers_only: bool,
+
+    /// Only print where the downloaded mails would go and which would be deleted, without
+    /// changing the store or committing anything.
+    #[arg(long, conflicts_with = "headers_only", global = true)]
+    dry_run: bool,
+
+    /// Only recompute the `Flagged` flag of the mails stored in this folder, without downloading
+    /// or moving any mails. Can be given multiple times.
+    #[arg(
+        long,
+        value_name = "FOLDER",
+        conflicts_with_all = ["dump_index", "bench", "watch", "headers_only", "dry_run"]
+    )]
+    reprocess_folder: Vec<String>,
+
+    /// Move new mails that are inconsistent with the store (e.g. a different mail with the same
+    /// `Message-ID`) into the `quarantine-folder` (`lkml-errors` by default) instead of aborting
+    /// the run.
+    #[arg(long, global = true)]
+    skip_errors: bool,
+
+    /// Run even if the last run was within the configured `cooldown`.
+    #[arg(long, global = true)]
+    force: bool,
+
+    /// Delete more mails than the configured `max-deletions`.
+    #[arg(long, global = true)]
+    allow_deletions: bool,
+
+    /// Only print errors, including those of `lei`.
+    ///
+    /// Keeps the log level at `error` (`RUST_LOG` still takes precedence) and passes `--quiet` to
+    /// `lei`, so its progress messages are not shown.
+    #[arg(long, short, conflicts_with = "verbose", global = true)]
+    quiet: bool,
+
+    /// Print more log messages, can be given up to three times (`info`, `debug`, `trace`).
+    ///
+    /// From `info` on, the duration and mail counts of every phase of the assortment are printed
+    /// as well. `RUST_LOG` takes precedence.
+    #[arg(long, short, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Keep running and update the mails periodically.
+    ///
+    /// The mail client is not launched in this mode. Stops on `SIGINT` and `SIGTERM`.
+    #[arg(long, conflicts_with_all = ["dump_index", "bench", "headers_only", "dry_run"])]
+    watch: bool,
+
+    /// Time to wait between two updates with `--watch`, e.g. `15m` or `1h 30m`.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        default_value = "15m",
+        requires = "watch",
+        value_parser = humantime::parse_duration
+    )]
+    every: Duration,
+
+    /// Randomly vary the time between two updates by up to this many percent, to avoid everyone
+    /// querying lore at the same time. `0` disables it.
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 10,
+        requires = "watch",
+        value_parser = clap::value_parser!(u8).range(0..=100)
+    )]
+    jitter: u8,
+
+    /// Stop `--watch` when an update fails instead of trying again at the next interval.
+    #[arg(long, requires = "watch")]
+    exit_on_error: bool,
+
+    /// Assort the mails in this maildir without downloading or moving anything and print timings.
+    #[arg(
+        long,
+        value_name = "DIR",
+        hide = true,
+        conflicts_with_all = ["headers_only", "dry_run"]
+    )]
+    bench: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Cmd {
+    /// Check the stored mails for inconsistencies without modifying anything.
+    ///
+    /// Exits with a nonzero status if any were found.
+    Verify,
+    /// Find mails that are stored multiple times and remove the superfluous copies.
+    DedupStore {
+        /// Actually remove the copies instead of only listing them.
+        #[arg(long)]
+        apply: bool,
+    },
+    /// Sort the flags in the file names of the stored mails, as required by maildir.
+    FixFlags,
+    /// Check the folder configuration for folders and rules that can never match.
+    ///
+    /// Exits with a nonzero status if any problems were found.
+    CheckConfig,
+    /// Only download the mails into a maildir, to assort them later with `assort`.
+    ///
+    /// The store is not modified, but the time of the last run is updated, so the next `fetch`
+    /// (or run) with `last` continues after these mails.
+    Fetch {
+        /// The maildir to put the mails into, created if it does not exist.
+        dir: PathBuf,
+        /// The amount of time to scan back, see the main command. Defaults to `day`.
+        interval: Option<Interval>,
+        /// Download at most this many mails per query.
+        ///
+        /// Every run returns the same mails for the same query, see the main command.
+        #[arg(long, value_name = "N")]
+        limit: Option<usize>,
+    },
+    /// Assort the stored mails again with the current folder rules, moving them between folders.
+    ///
+    /// Only prints what would be done unless `--confirm` is given. Mails that are ignored or
+    /// blocked now are deleted, up to `max-deletions`.
+    Reassort {
+        /// Actually move the mails instead of only printing where they would go.
+        #[arg(long)]
+        confirm: bool,
+    },
+    /// Assort the mails of a maildir downloaded by `fetch` into the store, instead of downloading
+    /// them.
+    ///
+    /// The mails are moved out of the maildir. The time of the last run is not updated, since the
+    /// mails might have been downloaded a while ago, and the `cooldown` does not apply.
+    Assort {
+        /// The maildir with the downloaded mails.
+        dir: PathBuf,
+    },
+}
+
+/// Where the new mails come from.
+#[derive(Clone, Copy, Debug)]
+enum Source<'a> {
+    /// Download them with `lei`.
+    Lei(Search, lei::Exec),
+    /// Take them out of a maildir filled by `lkml fetch`.
+    Dir(&'a Path),
 }
 
 fn main() -> Result<ExitCode> {
+    let args = Args::parse();
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::ERROR,
+        (false, 1) => LevelFilter::INFO,
+        (false, 2) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
-        .with(EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE))
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(level.into())
+                .from_env_lossy(),
+        )
         .init();
-    let args = Args::parse();
-    let config = config::load()?;
+    let config = match &args.config {
+        Some(path) => config::load_from(path)?,
+        None => config::load()?,
+    };
     debug!("loaded config: {config:#?}");
+    let exec = lei::Exec {
+        retries: config.lei_retries,
+        timeout: config.lei_timeout.map(Duration::from_secs),
+        quiet: args.quiet,
+    };
+    match &args.command {
+        Some(Cmd::Verify) => {
+            let problems = assort::verify(Maildir::from(config.path.clone()), &config)?;
+            if problems == 0 {
+                println!("no problems found.");
+            } else {
+                println!("found {problems} problem(s).");
+                return Ok(ExitCode::FAILURE);
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Cmd::DedupStore { apply }) => {
+            let _lock = state::lock(&config.path, Duration::from_secs(config.lock_wait))?;
+            let removed = assort::dedup_store(Maildir::from(config.path.clone()), &config, *apply)?;
+            if *apply {
+                println!("removed {removed} duplicate(s).");
+            } else {
+                println!("found {removed} duplicate(s), use `--apply` to remove them.");
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Cmd::FixFlags) => {
+            let _lock = state::lock(&config.path, Duration::from_secs(config.lock_wait))?;
+            let fixed = assort::fix_flags(Maildir::from(config.path.clone()), &config)?;
+            println!("fixed the flags of {fixed} mail(s).");
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Cmd::CheckConfig) => {
+            let problems = config.problems();
+            for problem in &problems {
+                println!("{problem}");
+            }
+            if problems.is_empty() {
+                println!("no problems found.");
+            } else {
+                println!("found {} problem(s).", problems.len());
+                return Ok(ExitCode::FAILURE);
+            }
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Cmd::Fetch {
+            dir,
+            interval,
+            limit,
+        }) => {
+            if limit.is_some() && config.saved_search.is_some() {
+                bail!("`--limit` cannot be used with `saved-search`");
+            }
+            let search = Search {
+                interval: interval.unwrap_or(Interval::Day),
+                limit: *limit,
+            };
+            let lock = state::lock(&config.path, Duration::from_secs(config.lock_wait))?;
+            let started = SystemTime::now();
+            let Some(new) = fetch(search, exec, &config, &lock)? else {
+                lock.finished(started)?;
+                return Ok(ExitCode::SUCCESS);
+            };
+            let downloaded = Maildir::from(new.path().to_owned());
+            let count = downloaded.count_new() + downloaded.count_cur();
+            Maildir::from(dir.clone()).create_dirs()?;
+            lei::take(new.path(), dir)?;
+            lock.finished(started)?;
+            println!("downloaded {count} mail(s) into `{}`.", dir.display());
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Cmd::Assort { .. } | Cmd::Reassort { .. }) | None => {}
+    }
+    if !args.reprocess_folder.is_empty() {
+        let _lock = state::lock(&config.path, Duration::from_secs(config.lock_wait))?;
+        let changed = assort::reprocess(
+            Maildir::from(config.path.clone()),
+            &config,
+            &args.reprocess_folder,
+        )?;
+        println!("changed the flags of {changed} mail(s).");
+        return Ok(ExitCode::SUCCESS);
+    }
+    let opts = assort::Options {
+        only_new: args.only_new,
+        parse_concurrency: args.parse_concurrency,
+        dump_unmatched: args.dump_unmatched.as_deref(),
+        dump_unmatched_lines: args.dump_unmatched_lines,
+        explain_dedup: args.explain_dedup,
+        headers_only: args.headers_only,
+        allow_deletions: args.allow_deletions,
+        dry_run: args.dry_run,
+        report: args.report.as_deref(),
+        skip_errors: args.skip_errors,
+        reassort: false,
+    };
+    if let Some(Cmd::Reassort { confirm }) = &args.command {
+        return reassort(&config, opts, *confirm);
+    }
+    if let Some(Cmd::Assort { dir }) = &args.command {
+        return run(
+            Source::Dir(dir),
+            &config.path,
+            &config,
+            opts,
+            None,
+            true,
+            args.force,
+        );
+    }
+    if let Some(corpus) = args.bench {
+        assort::bench(
+            Maildir::from(corpus),
+            Maildir::from(config.path.clone()),
+            &config,
+            opts,
+        )?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.limit.is_some() && config.saved_search.is_some() {
+        bail!("`--limit` cannot be used with `saved-search`");
+    }
+    let search = Search {
+        interval: args.interval.unwrap_or(Interval::Day),
+        limit: args.limit,
+    };
+    if args.print_query {
+        if config.saved_search.is_some() {
+            bail!("`--print-query` cannot be used with `saved-search`");
+        }
+        // resolve `last` like the update would.
+        let search = Search {
+            interval: search
+                .interval
+                .resolve(state::since_last_run(&config.path)?),
+            ..search
+        };
+        let queries = config.queries();
+        for (inbox, query) in &queries {
+            let query = lei::query_string(search, query);
+            if queries.len() == 1 {
+                println!("{query}");
+            } else {
+                println!("{inbox} {query}");
+            }
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.watch {
+        return watch(
+            search,
+            exec,
+            &config,
+            opts,
+            args.every,
+            args.jitter,
+            args.exit_on_error,
+        );
+    }
     run(
-        args.interval.unwrap_or(Interval::Day),
+        Source::Lei(search, exec),
         &config.path,
         &config,
+        opts,
+        args.dump_index.as_deref(),
+        true,
+        args.force,
     )
 }
 
-fn run(interval: Interval, store: &Path, config: &Config) -> Result<ExitCode> {
-    if let Some(git) = &config.git {
-        if !git::is_clean(store)? {
-            eprintln!("git repository not clean, refusing to update emails.");
+/// Assorts the stored mails again, see [`assort::reassort`].
+///
+/// With `confirm`, the store is pulled before and the changes are committed after.
+fn reassort(config: &Config, opts: assort::Options<'_>, confirm: bool) -> Result<ExitCode> {
+    let _lock = state::lock(&config.path, Duration::from_secs(config.lock_wait))?;
+    let git = config.git.as_ref().filter(|_| confirm);
+    if let Some(git) = git {
+        if !git::is_clean(&config.path)? {
+            eprintln!("git repository not clean, refusing to reassort emails.");
             return Ok(ExitCode::FAILURE);
         }
         if git.pull {
-            git::pull(store)?;
+            git::pull(git, &config.path)?;
         }
     }
-    let new = lei::query(interval, &config.query)?;
-    assort::run(new, Maildir::from(store.to_owned()), config)?;
-    let mut did_commit = false;
-    if config.git.is_some() && !git::is_clean(store)? {
-        git::add(store)?;
-        git::commit("update", store)?;
-        did_commit = true;
+    let summary = assort::reassort(Maildir::from(config.path.clone()), config, opts, confirm)?;
+    print!("{summary}");
+    if let Some(git) = git {
+        if !git::is_clean(&config.path)? {
+            git::add(&config.path)?;
+            git::commit(&format!("reassort\n\n{summary}"), git, &config.path)?;
+        }
     }
-    if let Some(cfg) = &config.client {
-        client(&cfg.command, store)?;
+    if !confirm {
+        println!("use `--confirm` to move the mails.");
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Repeatedly runs [`run`] every `every` (varied by `jitter` percent) until a termination signal is
+/// received.
+///
+/// Updates never overlap, if one takes longer than `every`, the next one starts right after it.
+fn watch(
+    search: Search,
+    exec: lei::Exec,
+    config: &Config,
+    opts: assort::Options<'_>,
+    every: Duration,
+    jitter: u8,
+    exit_on_error: bool,
+) -> Result<ExitCode> {
+    let (stop, stopped) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = stop.send(());
+    })?;
+    loop {
+        info!("updating mails");
+        let source = Source::Lei(search, exec);
+        // `--force` only skips the cooldown of a single run, not of every update.
+        if let Err(err) = run(source, &config.path, config, opts, None, false, false) {
+            if exit_on_error {
+                return Err(err);
+            }
+            error!("update failed: {err:#}");
+        }
+        let jitter = (fastrand::f64() * 2.0 - 1.0) * f64::from(jitter) / 100.0;
+        match stopped.recv_timeout(every.mul_f64(1.0 + jitter)) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => {
+                info!("received termination signal, stopping");
+                return Ok(ExitCode::SUCCESS);
+            }
+        }
+    }
+}
+
+fn run(
+    source: Source<'_>,
+    store: &Path,
+    config: &Config,
+    opts: assort::Options<'_>,
+    dump_index: Option<&Path>,
+    launch_client: bool,
+    force: bool,
+) -> Result<ExitCode> {
+    if !store.exists() {
+        Maildir::from(store.to_owned()).create_dirs()?;
+        println!("initialized new store at `{}`.", store.display());
+    }
+    let lock = state::lock(store, Duration::from_secs(config.lock_wait))?;
+    // the cooldown is against querying lore too often, mails from a maildir are fine.
+    let force = force || matches!(source, Source::Dir(_));
+    if let (Some(cooldown), Some(since), false) = (config.cooldown, lock.since_last_run()?, force) {
+        if since < Duration::from_secs(cooldown) {
+            eprintln!(
+                "last run finished {}s ago, skipping because of the cooldown (use `--force` to run anyway).",
+                since.as_secs()
+            );
+            return Ok(ExitCode::SUCCESS);
+        }
+    }
+    let mut stashed = false;
+    if let Some(git) = &config.git {
+        if !git::is_clean(store)? {
+            if !git.stash {
+                eprintln!("git repository not clean, refusing to update emails.");
+                return Ok(ExitCode::FAILURE);
+            }
+            git::stash(store)?;
+            stashed = true;
+        }
+    }
+    let updated = update(source, store, config, opts, dump_index, &lock);
+    if stashed {
+        match git::stash_pop(store) {
+            Ok(()) => {}
+            // don't hide the reason why the update failed.
+            Err(err) if updated.is_err() => error!("{err}"),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let Some(mut did_commit) = updated? else {
+        return Ok(ExitCode::SUCCESS);
+    };
+    if let Some(cfg) = config.client.as_ref().filter(|_| launch_client) {
+        if config.quiet_hours.as_ref().is_some_and(|q| q.active()) {
+            info!("quiet hours, not launching the mail client");
+        } else {
+            client(&cfg.command, store)?;
+        }
     }
     if let Some(git) = &config.git {
         if !git::is_clean(store)? {
             git::add(store)?;
-            git::commit("read", store)?;
+            git::commit("read", git, store)?;
             did_commit = true;
         }
-        if git.push && did_commit {
-            git::push(store)?;
+        if git.push && (did_commit || git.push_always) {
+            git::push(git, store)?;
+        } else if git.push {
+            info!("no new commits, not pushing");
         }
     }
     Ok(ExitCode::SUCCESS)
 }
 
+/// Pulls the store, downloads the new mails (or takes them from a maildir), assorts them and
+/// commits the result.
+///
+/// Returns whether a commit was created, or `None` if nothing was delivered (e.g. with
+/// `--dry-run`).
+fn update(
+    source: Source<'_>,
+    store: &Path,
+    config: &Config,
+    opts: assort::Options<'_>,
+    dump_index: Option<&Path>,
+    lock: &state::Lock,
+) -> Result<Option<bool>> {
+    if let Some(git) = config.git.as_ref().filter(|git| git.pull && !opts.dry_run) {
+        git::pull(git, store)?;
+    }
+    // keeps the downloaded mails alive until they are assorted.
+    let fetched;
+    // when the query started, only set for lei.
+    let mut started = None;
+    let new = match source {
+        Source::Lei(search, exec) => {
+            let now = SystemTime::now();
+            started = Some(now);
+            let Some(new) = fetch(search, exec, config, lock)? else {
+                if !opts.dry_run {
+                    lock.finished(now)?;
+                }
+                return Ok(None);
+            };
+            fetched = new;
+            Maildir::from(fetched.path().to_owned())
+        }
+        Source::Dir(dir) => {
+            let new = Maildir::from(dir.to_owned());
+            if new.count_new() + new.count_cur() == 0 {
+                println!("no mails in `{}`.", dir.display());
+                return Ok(None);
+            }
+            new
+        }
+    };
+    let Some(summary) = assort_new(new, store, config, opts, dump_index)? else {
+        return Ok(None);
+    };
+    if let Some(started) = started {
+        lock.finished(started)?;
+    }
+    let mut did_commit = false;
+    if let Some(git) = &config.git {
+        if !git::is_clean(store)? {
+            git::add(store)?;
+            git::commit(&summary.commit_message(git.folder_trailers), git, store)?;
+            did_commit = true;
+        }
+    }
+    Ok(Some(did_commit))
+}
+
+/// Downloads the new mails with `lei`, returns `None` if there are none.
+fn fetch(
+    search: Search,
+    exec: lei::Exec,
+    config: &Config,
+    lock: &state::Lock,
+) -> Result<Option<TempDir>> {
+    let search = Search {
+        interval: search.interval.resolve(lock.since_last_run()?),
+        ..search
+    };
+    let new = match &config.saved_search {
+        Some(search) => lei::update(search, exec)?,
+        None => {
+            let cache = config.cache_dir.as_deref().map(|dir| lei::Cache {
+                dir,
+                ttl: Duration::from_secs(config.cache_ttl),
+            });
+            lei::query(search, &config.queries(), exec, cache)?
+        }
+    };
+    let downloaded = Maildir::from(new.path().to_owned());
+    if downloaded.count_new() + downloaded.count_cur() == 0 {
+        match &config.saved_search {
+            Some(_) => println!("no new mails in the saved search."),
+            None => println!("no mails found in {}.", search.interval),
+        }
+        return Ok(None);
+    }
+    Ok(Some(new))
+}
+
+/// Assorts the mails in `new` into the store.
+///
+/// Returns `None` if nothing was delivered, i.e. with `dump_index`, `--headers-only` or
+/// `--dry-run`.
+fn assort_new(
+    new: Maildir,
+    store: &Path,
+    config: &Config,
+    opts: assort::Options<'_>,
+    dump_index: Option<&Path>,
+) -> Result<Option<assort::Summary>> {
+    let main = Maildir::from(store.to_owned());
+    if let Some(path) = dump_index {
+        assort::dump_index(new, main, config, opts, path)?;
+        return Ok(None);
+    }
+    if opts.headers_only {
+        assort::preview(new, main, config, opts)?;
+        return Ok(None);
+    }
+    let summary = assort::run(new, main, config, opts)?;
+    print!("{summary}");
+    if opts.dry_run {
+        return Ok(None);
+    }
+    Ok(Some(summary))
+}
+
 #[derive(Debug, Error)]
 enum ClientError {
     #[error("could not execute custom mail client: {0}")]
@@ -114,3 +760,15 @@ fn client(cmd: &[String], store: &Path) -> Result<(), ClientError> {
             .unwrap_or(ClientError::Signal))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::Args;
+
+    #[test]
+    fn args() {
+        Args::command().debug_assert();
+    }
+}
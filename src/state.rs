@@ -0,0 +1,114 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use directories_next::BaseDirs;
+use fs4::fs_std::FileExt;
+use thiserror::Error;
+
+use crate::hash::fnv1a;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("unable to locate user's home directory, is `$HOME` set?")]
+    NoHome,
+    #[error("failed to access state file `{1}`: {0}")]
+    Io(io::Error, PathBuf),
+    #[error("another instance of `lkml` is already running on `{0}`")]
+    Locked(PathBuf),
+}
+
+/// Exclusive access to a store and its state.
+///
+/// The state is kept in the local data directory of the user, separately for every store. The
+/// lock is released when this is dropped.
+pub struct Lock {
+    _file: File,
+    dir: PathBuf,
+}
+
+/// Returns the directory with the state of `store`.
+///
+/// It is named after a hash of the canonical path, so every path to the same store shares it.
+fn state_dir(store: &Path) -> Result<PathBuf, Error> {
+    let path = match fs::canonicalize(store) {
+        Ok(path) => path,
+        // the store is only created by the first run.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            std::path::absolute(store).map_err(|e| Error::Io(e, store.to_owned()))?
+        }
+        Err(e) => return Err(Error::Io(e, store.to_owned())),
+    };
+    let name = format!("{:016x}", fnv1a(path.as_os_str().as_encoded_bytes()));
+    Ok(BaseDirs::new()
+        .ok_or(Error::NoHome)?
+        .data_local_dir()
+        .join("lkml")
+        .join(name))
+}
+
+/// Locks `store`, waiting up to `wait` for another process holding the lock.
+pub fn lock(store: &Path, wait: Duration) -> Result<Lock, Error> {
+    let dir = state_dir(store)?;
+    fs::create_dir_all(&dir).map_err(|e| Error::Io(e, dir.clone()))?;
+    let path = dir.join("lock");
+    let file = File::create(&path).map_err(|e| Error::Io(e, path.clone()))?;
+    let start = Instant::now();
+    while !file
+        .try_lock_exclusive()
+        .map_err(|e| Error::Io(e, path.clone()))?
+    {
+        if start.elapsed() >= wait {
+            return Err(Error::Locked(store.to_owned()));
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    Ok(Lock { _file: file, dir })
+}
+
+/// Returns the time since the last completed run on `store` without locking it, if there was one.
+///
+/// Only for reading, a concurrent run might finish right after this returns.
+pub fn since_last_run(store: &Path) -> Result<Option<Duration>, Error> {
+    read_last_run(&state_dir(store)?)
+}
+
+fn read_last_run(dir: &Path) -> Result<Option<Duration>, Error> {
+    let path = dir.join("last-run");
+    let secs = match fs::read_to_string(&path) {
+        Ok(secs) => secs,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::Io(e, path)),
+    };
+    let Ok(secs) = secs.trim().parse() else {
+        return Ok(None);
+    };
+    let last = UNIX_EPOCH + Duration::from_secs(secs);
+    Ok(Some(
+        SystemTime::now().duration_since(last).unwrap_or_default(),
+    ))
+}
+
+impl Lock {
+    /// Returns the time since the last completed run, if there was one.
+    pub fn since_last_run(&self) -> Result<Option<Duration>, Error> {
+        read_last_run(&self.dir)
+    }
+
+    /// Records that a run which started at `started` has been completed.
+    ///
+    /// The start time is recorded, so mails arriving while the run was querying are not missed by
+    /// the next one.
+    pub fn finished(&self, started: SystemTime) -> Result<(), Error> {
+        let path = self.dir.join("last-run");
+        let secs = started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        fs::write(&path, format!("{secs}\n")).map_err(|e| Error::Io(e, path))
+    }
+}